@@ -1,5 +1,101 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryFrom;
-use std::io;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::iter::Peekable;
+use std::ops::RangeInclusive;
+use std::str::Chars;
+
+/// Passport fields as named by the keys in the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Field {
+    Byr,
+    Iyr,
+    Eyr,
+    Hgt,
+    Hcl,
+    Ecl,
+    Pid,
+    Cid,
+}
+
+impl Field {
+    const ALL: [Field; 8] = [
+        Field::Byr,
+        Field::Iyr,
+        Field::Eyr,
+        Field::Hgt,
+        Field::Hcl,
+        Field::Ecl,
+        Field::Pid,
+        Field::Cid,
+    ];
+
+    fn from_key(key: &str) -> Option<Field> {
+        Field::ALL.iter().copied().find(|f| f.key() == key)
+    }
+
+    fn key(&self) -> &'static str {
+        match self {
+            Field::Byr => "byr",
+            Field::Iyr => "iyr",
+            Field::Eyr => "eyr",
+            Field::Hgt => "hgt",
+            Field::Hcl => "hcl",
+            Field::Ecl => "ecl",
+            Field::Pid => "pid",
+            Field::Cid => "cid",
+        }
+    }
+}
+
+/// Reason why a passport is rejected by the rules of challenge2.
+#[derive(Debug, PartialEq)]
+enum Violation {
+    Missing(Field),
+    NotANumber(Field),
+    YearOutOfRange(Field, usize),
+    HeightOutOfRange(usize, &'static str),
+    BadHeightUnit,
+    MalformedHairColor,
+    InvalidEyeColor,
+    MalformedPassportId,
+}
+
+impl Violation {
+    /// Rule which is violated, used to aggregate violations over
+    /// multiple passports.
+    fn rule(&self) -> (Field, &'static str) {
+        match self {
+            Violation::Missing(f) => (*f, "missing"),
+            Violation::NotANumber(f) => (*f, "not a number"),
+            Violation::YearOutOfRange(f, _) => (*f, "out of range"),
+            Violation::HeightOutOfRange(..) => (Field::Hgt, "out of range"),
+            Violation::BadHeightUnit => (Field::Hgt, "bad unit"),
+            Violation::MalformedHairColor => (Field::Hcl, "malformed"),
+            Violation::InvalidEyeColor => (Field::Ecl, "invalid"),
+            Violation::MalformedPassportId => (Field::Pid, "malformed"),
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::YearOutOfRange(field, year) => {
+                write!(f, "{}: {} out of range", field.key(), year)
+            }
+            Violation::HeightOutOfRange(hgt, unit) => {
+                write!(f, "hgt: {}{} out of range", hgt, unit)
+            }
+            _ => {
+                let (field, rule) = self.rule();
+                write!(f, "{}: {}", field.key(), rule)
+            }
+        }
+    }
+}
 
 // Revert back to un-typed fields because in challenge1 input values
 // contain invalid data, maybe challenge2 adds extra validation.
@@ -16,6 +112,32 @@ struct Passport {
 }
 
 impl Passport {
+    fn get(&self, field: Field) -> &Option<String> {
+        match field {
+            Field::Byr => &self.byr,
+            Field::Iyr => &self.iyr,
+            Field::Eyr => &self.eyr,
+            Field::Hgt => &self.hgt,
+            Field::Hcl => &self.hcl,
+            Field::Ecl => &self.ecl,
+            Field::Pid => &self.pid,
+            Field::Cid => &self.cid,
+        }
+    }
+
+    fn get_mut(&mut self, field: Field) -> &mut Option<String> {
+        match field {
+            Field::Byr => &mut self.byr,
+            Field::Iyr => &mut self.iyr,
+            Field::Eyr => &mut self.eyr,
+            Field::Hgt => &mut self.hgt,
+            Field::Hcl => &mut self.hcl,
+            Field::Ecl => &mut self.ecl,
+            Field::Pid => &mut self.pid,
+            Field::Cid => &mut self.cid,
+        }
+    }
+
     /// Validate passport for challenge1.
     ///
     /// The passport must contain data in all fields except `cid` can be empty.
@@ -36,134 +158,703 @@ impl Passport {
     ///  - iyr (Issue Year): Four digits; at least 2010 and at most 2020.
     ///  - eyr (Expiration Year): four digits; at least 2020 and at most 2030.
     ///  - hgt (Height): A number followed by either cm or in:
-    ///                  If cm, the number must be at least 150 and at most 193.
-    ///                  If in, the number must be at least 59 and at most 76.
+    ///    If cm, the number must be at least 150 and at most 193.
+    ///    If in, the number must be at least 59 and at most 76.
     ///  - hcl (Hair Color): A # followed by exactly six characters 0-9 or a-f.
     ///  - ecl (Eye Color): Exactly one of: amb blu brn gry grn hzl oth.
     ///  - pid (Passport ID): A nine-digit number, including leading zeroes.
     ///  - cid (Country ID): Ignored, missing or not.
     fn valid2(&self) -> bool {
-        self._valid2().is_some()
+        self.violations().is_empty()
     }
 
-    fn _valid2(&self) -> Option<()> {
-        // Validate Birth Year.
-        let byr: usize = self.byr.as_ref()?.parse().ok()?;
-        if byr < 1920 || byr > 2002 {
-            return None;
-        }
-
-        // Validate Issue Year.
-        let iyr: usize = self.iyr.as_ref()?.parse().ok()?;
-        if iyr < 2010 || iyr > 2020 {
-            return None;
-        }
+    /// Collect all violations of the challenge2 rules.
+    ///
+    /// In contrast to `valid2` all fields are checked, such that the
+    /// result tells every reason why a passport is rejected.
+    fn violations(&self) -> Vec<Violation> {
+        let mut violations = Vec::new();
 
-        // Validate Expiration Year.
-        let eyr: usize = self.eyr.as_ref()?.parse().ok()?;
-        if eyr < 2020 || eyr > 2030 {
-            return None;
-        }
+        // Validate Birth, Issue and Expiration Year.
+        check_year(Field::Byr, &self.byr, 1920..=2002, &mut violations);
+        check_year(Field::Iyr, &self.iyr, 2010..=2020, &mut violations);
+        check_year(Field::Eyr, &self.eyr, 2020..=2030, &mut violations);
 
         // Validate Height.
-        if let Some(hgt_str) = self.hgt.as_ref()?.strip_suffix("cm") {
-            let hgt: usize = hgt_str.parse().ok()?;
-            if hgt < 150 || hgt > 193 {
-                return None;
-            }
-        } else if let Some(hgt_str) = self.hgt.as_ref()?.strip_suffix("in") {
-            let hgt: usize = hgt_str.parse().ok()?;
-            if hgt < 59 || hgt > 76 {
-                return None;
+        match self.hgt.as_ref() {
+            Some(hgt) => {
+                let unit = match (hgt.strip_suffix("cm"), hgt.strip_suffix("in")) {
+                    (Some(hgt_str), _) => Some((hgt_str, "cm", 150..=193)),
+                    (_, Some(hgt_str)) => Some((hgt_str, "in", 59..=76)),
+                    _ => None,
+                };
+
+                match unit {
+                    Some((hgt_str, unit, range)) => match hgt_str.parse::<usize>() {
+                        Ok(hgt) if !range.contains(&hgt) => {
+                            violations.push(Violation::HeightOutOfRange(hgt, unit))
+                        }
+                        Ok(_) => {}
+                        Err(_) => violations.push(Violation::NotANumber(Field::Hgt)),
+                    },
+                    None => violations.push(Violation::BadHeightUnit),
+                }
             }
-        } else {
-            return None;
+            None => violations.push(Violation::Missing(Field::Hgt)),
         }
 
         // Validate Hair Color.
-        if let Some(hcl_str) = self.hcl.as_ref()?.strip_prefix("#") {
-            if !hcl_str.len() == 6 {
-                return None;
+        match self.hcl.as_ref() {
+            Some(hcl) => {
+                if !valid_hcl(hcl) {
+                    violations.push(Violation::MalformedHairColor);
+                }
             }
-            u32::from_str_radix(hcl_str, 16).ok()?;
-        } else {
-            return None;
+            None => violations.push(Violation::Missing(Field::Hcl)),
         }
 
         // Validate Eye Color.
-        if !["amb", "blu", "brn", "gry", "grn", "hzl", "oth"].contains(&self.ecl.as_ref()?) {
-            return None;
+        match self.ecl.as_ref() {
+            Some(ecl) => {
+                if !["amb", "blu", "brn", "gry", "grn", "hzl", "oth"].contains(&ecl.as_str()) {
+                    violations.push(Violation::InvalidEyeColor);
+                }
+            }
+            None => violations.push(Violation::Missing(Field::Ecl)),
         }
 
         // Validate Passport ID.
-        if self.pid.as_ref()?.len() != 9 {
-            return None;
+        match self.pid.as_ref() {
+            Some(pid) => {
+                if !valid_pid(pid) {
+                    violations.push(Violation::MalformedPassportId);
+                }
+            }
+            None => violations.push(Violation::Missing(Field::Pid)),
         }
 
-        Some(())
+        violations
     }
 }
 
-impl TryFrom<&str> for Passport {
-    type Error = io::Error;
+/// Hair color must be a `#` followed by exactly six characters `0-9`
+/// or `a-f`.
+///
+/// Uppercase hex digits are rejected, as are signs which would be
+/// accepted by `from_str_radix`.
+fn valid_hcl(hcl: &str) -> bool {
+    match hcl.strip_prefix('#') {
+        Some(hex) => hex.len() == 6 && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')),
+        None => false,
+    }
+}
 
-    fn try_from(value: &str) -> io::Result<Self> {
+/// Passport ID must be a nine-digit number, including leading zeroes.
+fn valid_pid(pid: &str) -> bool {
+    pid.len() == 9 && pid.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Check that the year in `value` is a number in the inclusive `range`.
+fn check_year(
+    field: Field,
+    value: &Option<String>,
+    range: RangeInclusive<usize>,
+    violations: &mut Vec<Violation>,
+) {
+    match value.as_ref().map(|v| v.parse::<usize>()) {
+        Some(Ok(year)) if !range.contains(&year) => {
+            violations.push(Violation::YearOutOfRange(field, year))
+        }
+        Some(Ok(_)) => {}
+        Some(Err(_)) => violations.push(Violation::NotANumber(field)),
+        None => violations.push(Violation::Missing(field)),
+    }
+}
+
+/// How the parser treats keys which are not a passport field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnknownKeys {
+    Reject,
+    Warn,
+    Ignore,
+}
+
+/// Problem found in a passport record.
+#[derive(Debug, PartialEq)]
+enum Issue {
+    MissingSeparator(String),
+    UnknownKey(String),
+    DuplicateKey(Field),
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Issue::MissingSeparator(entry) => write!(f, "No ':' in entry '{}'", entry),
+            Issue::UnknownKey(key) => write!(f, "Invalid key: {}", key),
+            Issue::DuplicateKey(field) => write!(f, "Duplicate key: {}", field.key()),
+        }
+    }
+}
+
+/// Problem found in the passport record with 1-based index `record`
+/// of a batch.
+#[derive(Debug, PartialEq)]
+struct Problem {
+    record: usize,
+    issue: Issue,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "record {}: {}", self.record, self.issue)
+    }
+}
+
+/// Passports of a batch that parsed fine, together with the warnings
+/// raised during parsing.
+struct Batch {
+    passports: Vec<Passport>,
+    warnings: Vec<Problem>,
+}
+
+struct PassportParser {
+    unknown_keys: UnknownKeys,
+}
+
+impl Default for PassportParser {
+    fn default() -> PassportParser {
+        PassportParser {
+            unknown_keys: UnknownKeys::Reject,
+        }
+    }
+}
+
+impl PassportParser {
+    /// Parse a single passport record.
+    ///
+    /// Every entry is split at its first `:` only, such that values
+    /// containing a `:` are kept intact.
+    ///
+    /// On success returns the passport and the issues demoted to
+    /// warnings, on failure returns all issues found in the record.
+    fn parse_record(&self, record: &str) -> Result<(Passport, Vec<Issue>), Vec<Issue>> {
         let mut p = Passport::default();
-        for entry in value.split_whitespace() {
-            let (k, v) = {
-                let mut tokens = entry.split(':');
-                (
-                    tokens.next().ok_or(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "Failed to extract key",
-                    ))?,
-                    tokens.next().ok_or(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "Failed to extract value",
-                    ))?,
-                )
+        let mut errors = Vec::new();
+        let mut warnings = Vec::new();
+
+        for entry in record.split_whitespace() {
+            let (k, v) = match entry.split_once(':') {
+                Some(kv) => kv,
+                None => {
+                    errors.push(Issue::MissingSeparator(String::from(entry)));
+                    continue;
+                }
             };
 
-            match k {
-                "byr" => p.byr = Some(String::from(v)),
-                "iyr" => p.iyr = Some(String::from(v)),
-                "eyr" => p.eyr = Some(String::from(v)),
-                "hgt" => p.hgt = Some(String::from(v)),
-                "hcl" => p.hcl = Some(String::from(v)),
-                "ecl" => p.ecl = Some(String::from(v)),
-                "pid" => p.pid = Some(String::from(v)),
-                "cid" => p.cid = Some(String::from(v)),
-                k @ _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("Invalid key: {}", k),
-                    ));
+            match Field::from_key(k) {
+                Some(field) => {
+                    let value = p.get_mut(field);
+                    if value.is_some() {
+                        errors.push(Issue::DuplicateKey(field));
+                    } else {
+                        *value = Some(String::from(v));
+                    }
                 }
+                None => match self.unknown_keys {
+                    UnknownKeys::Reject => errors.push(Issue::UnknownKey(String::from(k))),
+                    UnknownKeys::Warn => warnings.push(Issue::UnknownKey(String::from(k))),
+                    UnknownKeys::Ignore => {}
+                },
+            }
+        }
+
+        if errors.is_empty() {
+            Ok((p, warnings))
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Parse a batch of passport records separated by empty lines.
+    ///
+    /// Parsing continues after a faulty record, such that on failure
+    /// the problems of all records are returned.
+    fn parse_batch(&self, input: &str) -> Result<Batch, Vec<Problem>> {
+        let mut passports = Vec::new();
+        let mut errors = Vec::new();
+        let mut warnings = Vec::new();
+
+        for (idx, record) in input.split("\n\n").enumerate() {
+            let problem = |issue| Problem {
+                record: idx + 1,
+                issue,
             };
+            match self.parse_record(record) {
+                Ok((p, w)) => {
+                    passports.push(p);
+                    warnings.extend(w.into_iter().map(problem));
+                }
+                Err(e) => errors.extend(e.into_iter().map(problem)),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(Batch {
+                passports,
+                warnings,
+            })
+        } else {
+            Err(errors)
         }
-        Ok(p)
     }
 }
 
-fn challenge1() -> io::Result<usize> {
-    let passports: io::Result<Vec<_>> = aoc20::read_input_to_string("day04")
-        .split("\n\n")
-        .map(|data_raw| Passport::try_from(data_raw))
-        .collect();
-    Ok(passports?.iter().filter(|p| p.valid1()).count())
+impl TryFrom<&str> for Passport {
+    type Error = io::Error;
+
+    fn try_from(value: &str) -> io::Result<Self> {
+        match PassportParser::default().parse_record(value) {
+            Ok((p, _)) => Ok(p),
+            Err(issues) => Err(invalid_data(
+                issues
+                    .iter()
+                    .map(|i| i.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )),
+        }
+    }
+}
+
+/// Aggregated challenge2 validation results over a batch of passports.
+#[derive(Default)]
+struct ValidationSummary {
+    total: usize,
+    valid: usize,
+    /// Number of passports rejected by each rule.
+    rejected: BTreeMap<(Field, &'static str), usize>,
+}
+
+impl ValidationSummary {
+    fn new(passports: &[Passport]) -> ValidationSummary {
+        let mut summary = ValidationSummary::default();
+        for p in passports {
+            let rules: BTreeSet<_> = p.violations().iter().map(|v| v.rule()).collect();
+            if rules.is_empty() {
+                summary.valid += 1;
+            }
+            for rule in rules {
+                *summary.rejected.entry(rule).or_default() += 1;
+            }
+            summary.total += 1;
+        }
+        summary
+    }
+
+    /// Rules ordered by the number of passports they reject, most
+    /// rejecting rule first.
+    fn ranking(&self) -> Vec<((Field, &'static str), usize)> {
+        let mut ranking: Vec<_> = self.rejected.iter().map(|(r, n)| (*r, *n)).collect();
+        ranking.sort_by(|(_, n1), (_, n2)| n2.cmp(n1));
+        ranking
+    }
 }
 
-fn challenge2() -> io::Result<usize> {
-    let passports: io::Result<Vec<_>> = aoc20::read_input_to_string("day04")
-        .split("\n\n")
-        .map(|data_raw| Passport::try_from(data_raw))
+impl fmt::Display for ValidationSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} of {} passports valid", self.valid, self.total)?;
+        for ((field, rule), n) in self.ranking() {
+            writeln!(f, "  {:>5} rejected by {}: {}", n, field.key(), rule)?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Write passports as CSV with one column per field followed by the
+/// validity under both rule sets.
+///
+/// Missing fields are written as empty cells, empty fields as quoted
+/// empty cells `""`.
+fn write_csv(passports: &[Passport], w: &mut impl Write) -> io::Result<()> {
+    fn cell(v: &str) -> String {
+        if v.is_empty() || v.contains(&[',', '"', '\n', '\r'][..]) {
+            format!("\"{}\"", v.replace('"', "\"\""))
+        } else {
+            String::from(v)
+        }
+    }
+
+    let header: Vec<_> = Field::ALL.iter().map(|f| f.key()).collect();
+    writeln!(w, "{},valid1,valid2", header.join(","))?;
+    for p in passports {
+        for f in &Field::ALL {
+            match p.get(*f).as_deref() {
+                Some(v) => write!(w, "{},", cell(v))?,
+                None => write!(w, ",")?,
+            }
+        }
+        writeln!(w, "{},{}", p.valid1(), p.valid2())?;
+    }
+    Ok(())
+}
+
+/// Read passports from CSV as written by `write_csv`.
+///
+/// Columns are matched by the header, unknown columns (such as the
+/// exported validity) are ignored as the passports are re-validated
+/// anyway. An empty cell is a missing field, a quoted empty cell an
+/// empty one.
+fn read_csv(input: &str) -> io::Result<Vec<Passport>> {
+    fn end_cell(cell: &mut String, present: &mut bool) -> Option<String> {
+        let v = std::mem::take(cell);
+        let present = std::mem::take(present);
+        (present || !v.is_empty()).then_some(v)
+    }
+
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut cell = String::new();
+    let mut quoted = false;
+    // Whether the current cell started with a quote.
+    let mut present = false;
+
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    cell.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if cell.is_empty() && !present => {
+                quoted = true;
+                present = true;
+            }
+            ',' if !quoted => record.push(end_cell(&mut cell, &mut present)),
+            '\r' if !quoted => {}
+            '\n' if !quoted => {
+                record.push(end_cell(&mut cell, &mut present));
+                records.push(std::mem::take(&mut record));
+            }
+            c => cell.push(c),
+        }
+    }
+    if quoted {
+        return Err(invalid_data(String::from("Unterminated quoted CSV cell")));
+    }
+    if !cell.is_empty() || present || !record.is_empty() {
+        record.push(end_cell(&mut cell, &mut present));
+        records.push(record);
+    }
+
+    let mut records = records.into_iter();
+    let columns: Vec<_> = records
+        .next()
+        .ok_or_else(|| invalid_data(String::from("Missing CSV header")))?
+        .iter()
+        .map(|h| Field::from_key(h.as_deref().unwrap_or("")))
         .collect();
-    Ok(passports?.iter().filter(|p| p.valid2()).count())
+
+    records
+        .enumerate()
+        .map(|(idx, record)| {
+            if record.len() != columns.len() {
+                return Err(invalid_data(format!(
+                    "CSV record {} has {} cells, expected {}",
+                    idx + 1,
+                    record.len(),
+                    columns.len()
+                )));
+            }
+            let mut p = Passport::default();
+            for (field, v) in columns.iter().zip(record) {
+                if let Some(field) = field {
+                    *p.get_mut(*field) = v;
+                }
+            }
+            Ok(p)
+        })
+        .collect()
+}
+
+/// Write passports as JSON array of objects, with one member per field
+/// (`null` if missing) followed by the validity under both rule sets.
+fn write_json(passports: &[Passport], w: &mut impl Write) -> io::Result<()> {
+    fn string(v: &str) -> String {
+        let mut s = String::from("\"");
+        for c in v.chars() {
+            match c {
+                '"' => s.push_str("\\\""),
+                '\\' => s.push_str("\\\\"),
+                c if c.is_control() => s.push_str(&format!("\\u{:04x}", c as u32)),
+                c => s.push(c),
+            }
+        }
+        s.push('"');
+        s
+    }
+
+    writeln!(w, "[")?;
+    for (idx, p) in passports.iter().enumerate() {
+        write!(w, "  {{")?;
+        for f in &Field::ALL {
+            match p.get(*f) {
+                Some(v) => write!(w, "\"{}\": {}, ", f.key(), string(v))?,
+                None => write!(w, "\"{}\": null, ", f.key())?,
+            }
+        }
+        write!(
+            w,
+            "\"valid1\": {}, \"valid2\": {}}}",
+            p.valid1(),
+            p.valid2()
+        )?;
+        writeln!(w, "{}", if idx + 1 < passports.len() { "," } else { "" })?;
+    }
+    writeln!(w, "]")
+}
+
+/// Value of an object member in the JSON written by `write_json`.
+///
+/// Bools only appear for the exported validity which is not imported,
+/// hence their value is dropped.
+enum JsonValue {
+    Str(String),
+    Bool,
+    Null,
+}
+
+/// Minimal reader for JSON as written by `write_json`: an array of flat
+/// objects with string, bool or null members.
+struct JsonReader<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> JsonReader<'a> {
+    fn new(input: &'a str) -> JsonReader<'a> {
+        JsonReader {
+            chars: input.chars().peekable(),
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+        self.chars.peek().copied()
+    }
+
+    fn expect(&mut self, expected: char) -> io::Result<()> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.chars.next();
+                Ok(())
+            }
+            c => Err(invalid_data(format!(
+                "Expected '{}' in JSON, got {:?}",
+                expected, c
+            ))),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> io::Result<()> {
+        for expected in keyword.chars() {
+            if self.chars.next() != Some(expected) {
+                return Err(invalid_data(format!("Expected '{}' in JSON", keyword)));
+            }
+        }
+        Ok(())
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('u') => {
+                        let hex: String = self.chars.by_ref().take(4).collect();
+                        let c = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(std::char::from_u32)
+                            .ok_or_else(|| {
+                                invalid_data(format!("Invalid JSON escape \\u{}", hex))
+                            })?;
+                        s.push(c);
+                    }
+                    Some(c @ '"') | Some(c @ '\\') | Some(c @ '/') => s.push(c),
+                    c => return Err(invalid_data(format!("Invalid JSON escape {:?}", c))),
+                },
+                Some(c) => s.push(c),
+                None => return Err(invalid_data(String::from("Unterminated JSON string"))),
+            }
+        }
+    }
+
+    fn value(&mut self) -> io::Result<JsonValue> {
+        match self.peek() {
+            Some('"') => Ok(JsonValue::Str(self.string()?)),
+            Some('t') => self.keyword("true").map(|_| JsonValue::Bool),
+            Some('f') => self.keyword("false").map(|_| JsonValue::Bool),
+            Some('n') => self.keyword("null").map(|_| JsonValue::Null),
+            c => Err(invalid_data(format!(
+                "Unsupported JSON value starting with {:?}",
+                c
+            ))),
+        }
+    }
+
+    /// Read comma separated `item`s enclosed by `open` and `close`.
+    fn list<T>(
+        &mut self,
+        open: char,
+        close: char,
+        mut item: impl FnMut(&mut Self) -> io::Result<T>,
+    ) -> io::Result<Vec<T>> {
+        let mut items = Vec::new();
+        self.expect(open)?;
+        if self.peek() != Some(close) {
+            loop {
+                items.push(item(self)?);
+                if self.peek() != Some(',') {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+        self.expect(close)?;
+        Ok(items)
+    }
+}
+
+/// Read passports from JSON as written by `write_json`.
+///
+/// Unknown members (such as the exported validity) are ignored as the
+/// passports are re-validated anyway.
+fn read_json(input: &str) -> io::Result<Vec<Passport>> {
+    let mut reader = JsonReader::new(input);
+    let passports = reader.list('[', ']', |reader| {
+        let mut p = Passport::default();
+        let members = reader.list('{', '}', |reader| {
+            let key = reader.string()?;
+            reader.expect(':')?;
+            Ok((key, reader.value()?))
+        })?;
+        for (key, value) in members {
+            match (Field::from_key(&key), value) {
+                (Some(field), JsonValue::Str(v)) => *p.get_mut(field) = Some(v),
+                (Some(_), JsonValue::Null) => {}
+                (Some(_), JsonValue::Bool) => {
+                    return Err(invalid_data(format!("Expected string for '{}'", key)))
+                }
+                (None, _) => {}
+            }
+        }
+        Ok(p)
+    })?;
+
+    if reader.peek().is_some() {
+        return Err(invalid_data(String::from("Trailing data after JSON array")));
+    }
+    Ok(passports)
+}
+
+fn parse_passports(parser: &PassportParser) -> io::Result<Vec<Passport>> {
+    match parser.parse_batch(&aoc20::read_input_to_string("day04")) {
+        Ok(batch) => {
+            for w in &batch.warnings {
+                eprintln!("Warning: {}", w);
+            }
+            Ok(batch.passports)
+        }
+        Err(problems) => Err(invalid_data(
+            problems
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )),
+    }
+}
+
+fn challenge1(passports: &[Passport]) -> usize {
+    passports.iter().filter(|p| p.valid1()).count()
+}
+
+fn challenge2(passports: &[Passport]) -> usize {
+    passports.iter().filter(|p| p.valid2()).count()
+}
+
+fn usage() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Usage: day04 [--unknown-keys <reject|warn|ignore>] \
+         [export-csv <file> | export-json <file> | import-csv <file> | import-json <file>]",
+    )
 }
 
 fn main() -> io::Result<()> {
-    println!("Valid passports challenge1: {}", challenge1()?);
-    println!("Valid passports challenge2: {}", challenge2()?);
+    let mut args: Vec<_> = std::env::args().skip(1).collect();
+
+    let mut parser = PassportParser::default();
+    if args.first().map(String::as_str) == Some("--unknown-keys") {
+        parser.unknown_keys = match args.get(1).map(String::as_str) {
+            Some("reject") => UnknownKeys::Reject,
+            Some("warn") => UnknownKeys::Warn,
+            Some("ignore") => UnknownKeys::Ignore,
+            _ => return Err(usage()),
+        };
+        args.drain(..2);
+    }
+
+    match args.as_slice() {
+        [] => {
+            let passports = parse_passports(&parser)?;
+            println!("Valid passports challenge1: {}", challenge1(&passports));
+            println!("Valid passports challenge2: {}", challenge2(&passports));
+            print!("{}", ValidationSummary::new(&passports));
+        }
+        [cmd, path] if cmd == "export-csv" || cmd == "export-json" => {
+            let passports = parse_passports(&parser)?;
+            let mut w = BufWriter::new(File::create(path)?);
+            if cmd == "export-csv" {
+                write_csv(&passports, &mut w)?;
+            } else {
+                write_json(&passports, &mut w)?;
+            }
+            w.flush()?;
+            println!("Exported {} passports to '{}'", passports.len(), path);
+        }
+        [cmd, path] if cmd.starts_with("import-") => {
+            let mut input = String::new();
+            File::open(path)?.read_to_string(&mut input)?;
+            let passports = match cmd.as_str() {
+                "import-csv" => read_csv(&input)?,
+                "import-json" => read_json(&input)?,
+                _ => return Err(usage()),
+            };
+            println!(
+                "Valid passports challenge1: {}",
+                passports.iter().filter(|p| p.valid1()).count()
+            );
+            println!(
+                "Valid passports challenge2: {}",
+                passports.iter().filter(|p| p.valid2()).count()
+            );
+            print!("{}", ValidationSummary::new(&passports));
+        }
+        _ => return Err(usage()),
+    }
     Ok(())
 }
 
@@ -173,13 +864,201 @@ mod tests {
 
     #[test]
     fn check_challenge1() -> io::Result<()> {
-        assert_eq!(challenge1()?, 182);
+        let passports = parse_passports(&PassportParser::default())?;
+        assert_eq!(challenge1(&passports), 182);
         Ok(())
     }
 
     #[test]
     fn check_challenge2() -> io::Result<()> {
-        assert_eq!(challenge2()?, 109);
+        let passports = parse_passports(&PassportParser::default())?;
+        assert_eq!(challenge2(&passports), 109);
+        Ok(())
+    }
+
+    #[test]
+    fn check_violations() -> io::Result<()> {
+        let p = Passport::try_from("hgt:59cm ecl:zzz eyr:2038 hcl:74454a iyr:2023 pid:3556412378")?;
+        assert_eq!(
+            p.violations(),
+            vec![
+                Violation::Missing(Field::Byr),
+                Violation::YearOutOfRange(Field::Iyr, 2023),
+                Violation::YearOutOfRange(Field::Eyr, 2038),
+                Violation::HeightOutOfRange(59, "cm"),
+                Violation::MalformedHairColor,
+                Violation::InvalidEyeColor,
+                Violation::MalformedPassportId,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_valid_hcl() {
+        assert!(valid_hcl("#123abc"));
+        assert!(valid_hcl("#000000"));
+        assert!(!valid_hcl("#123ab"));
+        assert!(!valid_hcl("#123abcd"));
+        assert!(!valid_hcl("#123ABC"));
+        assert!(!valid_hcl("#123abz"));
+        assert!(!valid_hcl("#+12345"));
+        assert!(!valid_hcl("123abc"));
+        assert!(!valid_hcl("#"));
+    }
+
+    #[test]
+    fn check_valid_pid() {
+        assert!(valid_pid("000000001"));
+        assert!(valid_pid("123456789"));
+        assert!(!valid_pid("0123456789"));
+        assert!(!valid_pid("12345678"));
+        assert!(!valid_pid("12345678a"));
+        assert!(!valid_pid("+12345678"));
+        assert!(!valid_pid("１２３"));
+    }
+
+    #[test]
+    fn check_csv_roundtrip() -> io::Result<()> {
+        let mut p = Passport::try_from("byr:1937 hcl:#fffffd pid:860033327")?;
+        p.cid = Some(String::from("a,\"b\""));
+
+        let mut csv = Vec::new();
+        write_csv(&[p], &mut csv)?;
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(
+            csv,
+            "byr,iyr,eyr,hgt,hcl,ecl,pid,cid,valid1,valid2\n\
+             1937,,,,#fffffd,,860033327,\"a,\"\"b\"\"\",false,false\n"
+        );
+
+        let p = read_csv(&csv)?;
+        assert_eq!(p.len(), 1);
+        assert_eq!(p[0].byr.as_deref(), Some("1937"));
+        assert_eq!(p[0].iyr, None);
+        assert_eq!(p[0].cid.as_deref(), Some("a,\"b\""));
+
+        // Empty fields stay present, so validity is kept.
+        let p = Passport::try_from("byr: iyr:2012 eyr:2030 hgt:150cm hcl:#123abc ecl:amb pid:x")?;
+        assert_eq!(p.byr.as_deref(), Some(""));
+        assert!(p.valid1());
+        let mut csv = Vec::new();
+        write_csv(&[p], &mut csv)?;
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.contains("\n\"\",2012,"));
+        let p = read_csv(&csv)?;
+        assert_eq!(p[0].byr.as_deref(), Some(""));
+        assert_eq!(p[0].cid, None);
+        assert!(p[0].valid1());
+        Ok(())
+    }
+
+    #[test]
+    fn check_json_roundtrip() -> io::Result<()> {
+        let passports = parse_passports(&PassportParser::default())?;
+
+        let mut json = Vec::new();
+        write_json(&passports, &mut json)?;
+        let imported = read_json(&String::from_utf8(json).unwrap())?;
+
+        assert_eq!(imported.len(), passports.len());
+        for (p1, p2) in passports.iter().zip(&imported) {
+            for f in &Field::ALL {
+                assert_eq!(p1.get(*f), p2.get(*f));
+            }
+        }
+        assert_eq!(imported.iter().filter(|p| p.valid2()).count(), 109);
+        Ok(())
+    }
+
+    #[test]
+    fn check_parse_record() {
+        let parser = PassportParser::default();
+        let (p, warnings) = parser.parse_record("byr:1937\ncid:a:b").unwrap();
+        assert_eq!(p.byr.as_deref(), Some("1937"));
+        assert_eq!(p.cid.as_deref(), Some("a:b"));
+        assert!(warnings.is_empty());
+
+        assert_eq!(
+            parser.parse_record("byr:1937 foo:1 byr:1938 pid").err(),
+            Some(vec![
+                Issue::UnknownKey(String::from("foo")),
+                Issue::DuplicateKey(Field::Byr),
+                Issue::MissingSeparator(String::from("pid")),
+            ])
+        );
+    }
+
+    #[test]
+    fn check_parse_batch() {
+        let input = "byr:1937 foo:1\n\nbyr:1937\n\nfoo:2 iyr:2010 iyr:2011";
+
+        let mut parser = PassportParser::default();
+        assert_eq!(
+            parser.parse_batch(input).err(),
+            Some(vec![
+                Problem {
+                    record: 1,
+                    issue: Issue::UnknownKey(String::from("foo"))
+                },
+                Problem {
+                    record: 3,
+                    issue: Issue::UnknownKey(String::from("foo"))
+                },
+                Problem {
+                    record: 3,
+                    issue: Issue::DuplicateKey(Field::Iyr)
+                },
+            ])
+        );
+
+        parser.unknown_keys = UnknownKeys::Warn;
+        assert_eq!(
+            parser.parse_batch(input).err(),
+            Some(vec![Problem {
+                record: 3,
+                issue: Issue::DuplicateKey(Field::Iyr)
+            }])
+        );
+
+        let batch = parser.parse_batch("byr:1937 foo:1\n\nbyr:1937").unwrap();
+        assert_eq!(batch.passports.len(), 2);
+        assert_eq!(
+            batch.warnings,
+            vec![Problem {
+                record: 1,
+                issue: Issue::UnknownKey(String::from("foo"))
+            }]
+        );
+
+        parser.unknown_keys = UnknownKeys::Ignore;
+        let batch = parser.parse_batch("byr:1937 foo:1\n\nbyr:1937").unwrap();
+        assert_eq!(batch.passports.len(), 2);
+        assert!(batch.warnings.is_empty());
+    }
+
+    #[test]
+    fn check_summary() -> io::Result<()> {
+        let passports = [
+            "eyr:1972 cid:100 hcl:#18171d ecl:amb hgt:170 pid:186cm iyr:2018 byr:1926",
+            "iyr:2019 hcl:#602927 eyr:1967 hgt:170cm ecl:grn pid:012533040 byr:1946",
+            "pid:087499704 hgt:74in ecl:grn iyr:2012 eyr:2030 byr:1980 hcl:#623a2f",
+        ]
+        .iter()
+        .map(|p| Passport::try_from(*p))
+        .collect::<io::Result<Vec<_>>>()?;
+
+        let summary = ValidationSummary::new(&passports);
+        assert_eq!(summary.total, 3);
+        assert_eq!(summary.valid, 1);
+        assert_eq!(
+            summary.ranking(),
+            vec![
+                ((Field::Eyr, "out of range"), 2),
+                ((Field::Hgt, "bad unit"), 1),
+                ((Field::Pid, "malformed"), 1),
+            ]
+        );
         Ok(())
     }
 }
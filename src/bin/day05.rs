@@ -1,88 +1,448 @@
+use std::fmt;
 use std::io::{self, BufRead};
 use std::str::FromStr;
 
+/// Geometry of a plane, given by the number of bits encoding the row
+/// and the column of a seat in a boarding pass.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PlaneLayout {
+    row_bits: u32,
+    col_bits: u32,
+}
+
+impl Default for PlaneLayout {
+    /// Plane with 128 rows and 8 columns.
+    fn default() -> PlaneLayout {
+        PlaneLayout {
+            row_bits: 7,
+            col_bits: 3,
+        }
+    }
+}
+
+impl PlaneLayout {
+    fn new(row_bits: u32, col_bits: u32) -> io::Result<PlaneLayout> {
+        if row_bits.checked_add(col_bits).is_none_or(|b| b > 32) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seat uid must fit into 32 bits",
+            ));
+        }
+        Ok(PlaneLayout { row_bits, col_bits })
+    }
+
+    fn rows(&self) -> u64 {
+        1 << self.row_bits
+    }
+
+    fn cols(&self) -> u64 {
+        1 << self.col_bits
+    }
+
+    /// Length of a boarding pass string for this plane.
+    fn pass_len(&self) -> usize {
+        (self.row_bits + self.col_bits) as usize
+    }
+
+    /// Decode boarding pass string like `FBFBBFFRLR`.
+    ///
+    /// The first `row_bits` characters give the row in binary with `B`
+    /// as 1 and `F` as 0, the remaining `col_bits` characters give the
+    /// column in binary with `R` as 1 and `L` as 0.
+    fn decode(&self, s: &str) -> Result<BoardingPass, PassError> {
+        let len = s.chars().count();
+        if len != self.pass_len() {
+            return Err(PassError::Length {
+                len,
+                expected: self.pass_len(),
+            });
+        }
+
+        let mut chars = s.chars().enumerate();
+
+        let row = chars
+            .by_ref()
+            .take(self.row_bits as usize)
+            .try_fold(0u32, |sum, (pos, c)| match c {
+                'B' => Ok((sum << 1) | 1),
+                'F' => Ok(sum << 1),
+                _ => Err(PassError::Row { c, pos }),
+            })?;
+
+        let col = chars.try_fold(0u32, |sum, (pos, c)| match c {
+            'R' => Ok((sum << 1) | 1),
+            'L' => Ok(sum << 1),
+            _ => Err(PassError::Col { c, pos }),
+        })?;
+
+        Ok(BoardingPass {
+            layout: *self,
+            row,
+            col,
+        })
+    }
+
+    /// Compute the seat for a given seat `uid`, inverse of
+    /// `BoardingPass::uid`.
+    fn seat(&self, uid: u32) -> io::Result<BoardingPass> {
+        if u64::from(uid) >= self.rows() * self.cols() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seat uid out of range for plane layout",
+            ));
+        }
+
+        Ok(BoardingPass {
+            layout: *self,
+            row: (u64::from(uid) >> self.col_bits) as u32,
+            col: uid & (self.cols() - 1) as u32,
+        })
+    }
+}
+
+/// Reason why a boarding pass string can not be decoded.
+#[derive(Debug, PartialEq)]
+enum PassError {
+    /// Pass has `len` characters but the plane layout needs `expected`.
+    Length { len: usize, expected: usize },
+    /// Invalid character `c` at 0-based position `pos` in the row
+    /// specifier.
+    Row { c: char, pos: usize },
+    /// Invalid character `c` at 0-based position `pos` in the column
+    /// specifier.
+    Col { c: char, pos: usize },
+}
+
+impl fmt::Display for PassError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PassError::Length { len, expected } => {
+                write!(f, "Wrong pass length {}, expected {}", len, expected)
+            }
+            PassError::Row { c, pos } => {
+                write!(f, "Need B/F in row specifier, got {:?} at {}", c, pos)
+            }
+            PassError::Col { c, pos } => {
+                write!(f, "Need R/L in col specifier, got {:?} at {}", c, pos)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PassError {}
+
+impl From<PassError> for io::Error {
+    fn from(e: PassError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Error of the boarding pass on 1-based `line` of the input.
+#[derive(Debug, PartialEq)]
+struct LineError {
+    line: usize,
+    err: PassError,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.err)
+    }
+}
+
+impl std::error::Error for LineError {}
+
+impl From<LineError> for io::Error {
+    fn from(e: LineError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 struct BoardingPass {
+    layout: PlaneLayout,
     row: u32,
     col: u32,
 }
 
 impl BoardingPass {
     fn uid(&self) -> u32 {
-        // Just panic if overflow for now.
-        self.row * 8 + self.col
+        // Can not overflow as `PlaneLayout` ensures the uid fits into
+        // 32 bits.
+        ((u64::from(self.row) << self.layout.col_bits) | u64::from(self.col)) as u32
     }
 }
 
 impl FromStr for BoardingPass {
-    type Err = io::Error;
+    type Err = PassError;
 
+    /// Decode boarding pass for the default plane layout.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.len() != 10 {
+        PlaneLayout::default().decode(s)
+    }
+}
+
+impl fmt::Display for BoardingPass {
+    /// Encode boarding pass string, inverse of `PlaneLayout::decode`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for bit in (0..self.layout.row_bits).rev() {
+            write!(f, "{}", if (self.row >> bit) & 1 == 1 { 'B' } else { 'F' })?;
+        }
+        for bit in (0..self.layout.col_bits).rev() {
+            write!(f, "{}", if (self.col >> bit) & 1 == 1 { 'R' } else { 'L' })?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a seat has no boarding pass.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FreeReason {
+    /// Seat is in a row in front of the first occupied row, the row is
+    /// likely missing on this aircraft.
+    Front,
+    /// Seat is in a row behind the last occupied row, the row is likely
+    /// missing on this aircraft.
+    Back,
+    /// Seat is in or in between occupied rows.
+    Gap,
+}
+
+/// Maximum number of seats of a plane layout for a `SeatMap`.
+const MAX_MAP_SEATS: u64 = 1 << 24;
+
+/// Occupancy of all seats of a plane.
+struct SeatMap {
+    layout: PlaneLayout,
+    /// Number of boarding passes per seat, indexed by seat uid.
+    passes: Vec<u32>,
+}
+
+impl SeatMap {
+    /// Build occupancy map for `layout` from boarding `passes`.
+    ///
+    /// Allocates one entry per seat, hence layouts with more than
+    /// `MAX_MAP_SEATS` seats are rejected.
+    fn new(layout: PlaneLayout, passes: &[BoardingPass]) -> io::Result<SeatMap> {
+        if layout.rows() * layout.cols() > MAX_MAP_SEATS {
             return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Wrong input data length",
+                io::ErrorKind::InvalidInput,
+                "Plane layout has too many seats for a seat map",
             ));
         }
 
-        let row = s.chars().take(7).try_fold(0u32, |sum, c| match c {
-            'B' => Ok((sum << 1) | 1),
-            'F' => Ok((sum << 1) | 0),
-            _ => Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Need B/F in row specifier",
-            )),
-        })?;
+        let mut map = SeatMap {
+            layout,
+            passes: vec![0; (layout.rows() * layout.cols()) as usize],
+        };
+        for bp in passes {
+            if bp.layout != layout {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Boarding pass does not match plane layout",
+                ));
+            }
+            map.passes[bp.uid() as usize] += 1;
+        }
+        Ok(map)
+    }
 
-        let col = s.chars().skip(7).take(3).try_fold(0u32, |sum, c| match c {
-            'R' => Ok((sum << 1) | 1),
-            'L' => Ok((sum << 1) | 0),
-            _ => Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Need B/F in col specifier",
-            )),
-        })?;
+    fn occupied(&self, uid: u32) -> bool {
+        self.passes.get(uid as usize).is_some_and(|&n| n > 0)
+    }
 
-        Ok(BoardingPass { row, col })
+    /// All seats without boarding pass in uid order.
+    ///
+    /// Seats are classified by row: all seats of a row without any pass
+    /// in front of the first or behind the last occupied row are `Front`
+    /// or `Back`, every other free seat is a `Gap`.
+    fn free_seats(&self) -> Vec<(BoardingPass, FreeReason)> {
+        let cols = self.layout.cols() as usize;
+        let occupied_row = |row: &[u32]| row.iter().any(|&n| n > 0);
+        let first = self.passes.chunks(cols).position(occupied_row);
+        let last = self.passes.chunks(cols).rposition(occupied_row);
+
+        self.passes
+            .iter()
+            .enumerate()
+            .filter(|(_, &n)| n == 0)
+            .map(|(uid, _)| {
+                let row = uid / cols;
+                let reason = match (first, last) {
+                    (Some(first), _) if row < first => FreeReason::Front,
+                    (_, Some(last)) if row > last => FreeReason::Back,
+                    (Some(_), Some(_)) => FreeReason::Gap,
+                    // Empty plane, consider all seats as missing at the
+                    // front.
+                    _ => FreeReason::Front,
+                };
+                let seat = self.layout.seat(uid as u32).expect("uid in layout");
+                (seat, reason)
+            })
+            .collect()
+    }
+
+    /// All seats with more than one boarding pass, together with the
+    /// number of passes, in uid order.
+    fn duplicates(&self) -> Vec<(BoardingPass, u32)> {
+        self.passes
+            .iter()
+            .enumerate()
+            .filter(|(_, &n)| n > 1)
+            .map(|(uid, &n)| (self.layout.seat(uid as u32).expect("uid in layout"), n))
+            .collect()
     }
 }
 
-fn challenge1() -> io::Result<u32> {
-    let mut max = 0u32;
-    for l in aoc20::input_bufreader("day05").lines() {
-        let uid = l?.parse::<BoardingPass>()?.uid();
-        if uid > max {
-            max = uid;
+impl fmt::Display for SeatMap {
+    /// Render the plane with one line per row, `#` marks an occupied
+    /// seat, `.` a free seat and `!` a seat with multiple passes.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = (self.layout.rows() - 1).to_string().len();
+        for (row, seats) in self.passes.chunks(self.layout.cols() as usize).enumerate() {
+            write!(f, "{:>width$} ", row, width = width)?;
+            for n in seats {
+                let c = match n {
+                    0 => '.',
+                    1 => '#',
+                    _ => '!',
+                };
+                write!(f, "{}", c)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decode the boarding passes of all lines in `input`.
+///
+/// If `keep_going` is set, decoding continues past bad passes and the
+/// errors of all bad passes are returned, otherwise decoding stops at
+/// the first bad pass.
+fn decode_passes(
+    layout: &PlaneLayout,
+    input: impl BufRead,
+    keep_going: bool,
+) -> io::Result<(Vec<BoardingPass>, Vec<LineError>)> {
+    let mut passes = Vec::new();
+    let mut errors = Vec::new();
+
+    for (idx, l) in input.lines().enumerate() {
+        match layout.decode(&l?) {
+            Ok(bp) => passes.push(bp),
+            Err(err) => {
+                errors.push(LineError { line: idx + 1, err });
+                if !keep_going {
+                    break;
+                }
+            }
         }
     }
-    Ok(max)
+
+    Ok((passes, errors))
+}
+
+/// Decode the boarding passes of the input, failing on the first bad
+/// pass.
+fn parse_passes(layout: &PlaneLayout) -> io::Result<Vec<BoardingPass>> {
+    let (passes, mut errors) = decode_passes(layout, aoc20::input_bufreader("day05"), false)?;
+    match errors.pop() {
+        Some(e) => Err(e.into()),
+        None => Ok(passes),
+    }
+}
+
+fn challenge1() -> io::Result<u32> {
+    parse_passes(&PlaneLayout::default())?
+        .iter()
+        .map(BoardingPass::uid)
+        .max()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No boarding passes"))
 }
 
 fn challenge2() -> io::Result<u32> {
-    let bp_uids = {
-        let mut v = aoc20::input_bufreader("day05")
-            .lines()
-            .map(|l| Ok(l?.parse::<BoardingPass>()?.uid()))
-            .collect::<io::Result<Vec<_>>>()?;
-        v.sort();
-        v
-    };
-
-    Ok(bp_uids
-        .windows(2)
+    let layout = PlaneLayout::default();
+    let map = SeatMap::new(layout, &parse_passes(&layout)?)?;
+
+    map.free_seats()
+        .iter()
+        .map(|(seat, _)| seat.uid())
         // From challenge description:
         //   Your seat wasn't at the very front or back, though; the
         //   seats with IDs +1 and -1 from yours will be in your list.
-        .find(|w| w[1] - w[0] == 2)
-        .ok_or(io::Error::new(
-            io::ErrorKind::Other,
-            "No unused boarding pass uid found",
-        ))?[0]
-        + 1)
+        .find(|&uid| uid > 0 && map.occupied(uid - 1) && map.occupied(uid + 1))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No unused boarding pass uid"))
+}
+
+fn usage() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Usage: day05 [--layout <row_bits>,<col_bits>] [--keep-going] \
+         [decode <pass>.. | encode <uid>.. | map]",
+    )
 }
 
 fn main() -> io::Result<()> {
-    println!("{}", challenge1()?);
-    println!("{}", challenge2()?);
+    let mut args: Vec<_> = std::env::args().skip(1).collect();
+
+    let mut layout = PlaneLayout::default();
+    if args.first().map(String::as_str) == Some("--layout") {
+        let bits = args
+            .get(1)
+            .and_then(|l| l.split_once(','))
+            .and_then(|(r, c)| Some((r.parse().ok()?, c.parse().ok()?)))
+            .ok_or_else(usage)?;
+        layout = PlaneLayout::new(bits.0, bits.1)?;
+        args.drain(..2);
+    }
+
+    let keep_going = args.first().map(String::as_str) == Some("--keep-going");
+    if keep_going {
+        args.remove(0);
+    }
+
+    match args.split_first() {
+        None => {
+            println!("{}", challenge1()?);
+            println!("{}", challenge2()?);
+        }
+        Some((cmd, passes)) if cmd == "decode" => {
+            for pass in passes {
+                match layout.decode(pass) {
+                    Ok(bp) => println!("{} row {} col {} uid {}", bp, bp.row, bp.col, bp.uid()),
+                    Err(e) if keep_going => eprintln!("{}: {}", pass, e),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        Some((cmd, uids)) if cmd == "encode" => {
+            for uid in uids {
+                let bp = layout.seat(uid.parse().map_err(|_| usage())?)?;
+                println!("{} row {} col {} uid {}", bp, bp.row, bp.col, bp.uid());
+            }
+        }
+        Some((cmd, [])) if cmd == "map" => {
+            let input = aoc20::input_bufreader("day05");
+            let (passes, errors) = decode_passes(&layout, input, keep_going)?;
+            for e in errors {
+                if !keep_going {
+                    return Err(e.into());
+                }
+                eprintln!("{}", e);
+            }
+
+            let map = SeatMap::new(layout, &passes)?;
+            print!("{}", map);
+            for (seat, reason) in map.free_seats() {
+                println!("free {} uid {} ({:?})", seat, seat.uid(), reason);
+            }
+            for (seat, n) in map.duplicates() {
+                println!("duplicate {} uid {} ({} passes)", seat, seat.uid(), n);
+            }
+        }
+        Some(_) => return Err(usage()),
+    }
     Ok(())
 }
 
@@ -101,4 +461,123 @@ mod tests {
         assert_eq!(challenge2()?, 640);
         Ok(())
     }
+
+    #[test]
+    fn check_codec() -> io::Result<()> {
+        for (pass, row, col, uid) in &[
+            ("FBFBBFFRLR", 44, 5, 357),
+            ("BFFFBBFRRR", 70, 7, 567),
+            ("FFFBBBFRRR", 14, 7, 119),
+            ("BBFFBBFRLL", 102, 4, 820),
+        ] {
+            let bp = pass.parse::<BoardingPass>()?;
+            assert_eq!((bp.row, bp.col, bp.uid()), (*row, *col, *uid));
+            assert_eq!(PlaneLayout::default().seat(*uid)?.to_string(), *pass);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_seat_map() -> io::Result<()> {
+        let layout = PlaneLayout::new(2, 2)?;
+        let passes = ["FBLR", "FBRL", "FBRL", "BFLL", "BFRR", "BFLR"]
+            .iter()
+            .map(|p| layout.decode(p))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let map = SeatMap::new(layout, &passes)?;
+        assert_eq!(map.to_string(), "0 ....\n1 .#!.\n2 ##.#\n3 ....\n");
+
+        let free: Vec<_> = map
+            .free_seats()
+            .iter()
+            .map(|(seat, reason)| (seat.uid(), *reason))
+            .collect();
+        assert_eq!(
+            free,
+            vec![
+                (0, FreeReason::Front),
+                (1, FreeReason::Front),
+                (2, FreeReason::Front),
+                (3, FreeReason::Front),
+                (4, FreeReason::Gap),
+                (7, FreeReason::Gap),
+                (10, FreeReason::Gap),
+                (12, FreeReason::Back),
+                (13, FreeReason::Back),
+                (14, FreeReason::Back),
+                (15, FreeReason::Back),
+            ]
+        );
+
+        let dups: Vec<_> = map
+            .duplicates()
+            .iter()
+            .map(|(s, n)| (s.to_string(), *n))
+            .collect();
+        assert_eq!(dups, vec![(String::from("FBRL"), 2)]);
+
+        assert!(SeatMap::new(PlaneLayout::new(29, 3)?, &[]).is_err());
+        assert!(SeatMap::new(PlaneLayout::new(21, 3)?, &[]).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn check_errors() -> io::Result<()> {
+        let layout = PlaneLayout::default();
+        assert_eq!(
+            layout.decode("FBFBBFFRL").err(),
+            Some(PassError::Length {
+                len: 9,
+                expected: 10
+            })
+        );
+        assert_eq!(
+            layout.decode("FBFXBFFRLR").err(),
+            Some(PassError::Row { c: 'X', pos: 3 })
+        );
+        assert_eq!(
+            layout.decode("FBFBBFFRBR").err(),
+            Some(PassError::Col { c: 'B', pos: 8 })
+        );
+
+        let input = "FBFBBFFRLR\nFBFBBFFRLB\nBFFFBBFRRR\nFBFBBFFRL\n".as_bytes();
+        let (passes, errors) = decode_passes(&layout, input, false)?;
+        assert_eq!(passes.len(), 1);
+        assert_eq!(
+            errors,
+            vec![LineError {
+                line: 2,
+                err: PassError::Col { c: 'B', pos: 9 }
+            }]
+        );
+
+        let (passes, errors) = decode_passes(&layout, input, true)?;
+        assert_eq!(passes.len(), 2);
+        let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "line 2: Need R/L in col specifier, got 'B' at 9",
+                "line 4: Wrong pass length 9, expected 10",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_custom_layout() -> io::Result<()> {
+        let layout = PlaneLayout::new(5, 2)?;
+        let bp = layout.decode("BFFBFRL")?;
+        assert_eq!((bp.row, bp.col, bp.uid()), (18, 2, 74));
+        assert_eq!(bp.to_string(), "BFFBFRL");
+        assert!(layout.seat(128).is_err());
+        assert!(layout.decode("FBFBBFFRLR").is_err());
+        assert!(PlaneLayout::new(30, 3).is_err());
+        assert!(PlaneLayout::new(u32::MAX, 1).is_err());
+
+        let layout = PlaneLayout::new(29, 3)?;
+        assert_eq!(layout.seat(u32::MAX)?.to_string().len(), 32);
+        Ok(())
+    }
 }
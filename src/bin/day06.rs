@@ -1,69 +1,352 @@
+use std::collections::{BTreeMap, HashSet};
 use std::io;
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+
+/// Set of questions answered with yes, each question is identified by
+/// a `char`.
+#[derive(Debug, Clone)]
+enum AnswerSet {
+    /// Bitset for questions in the ASCII range.
+    Ascii(u128),
+    /// Hash set used as soon as any question is outside the ASCII range.
+    Any(HashSet<char>),
+}
+
+impl Default for AnswerSet {
+    fn default() -> AnswerSet {
+        AnswerSet::Ascii(0)
+    }
+}
+
+impl PartialEq for AnswerSet {
+    fn eq(&self, other: &AnswerSet) -> bool {
+        match (self, other) {
+            (AnswerSet::Ascii(b1), AnswerSet::Ascii(b2)) => b1 == b2,
+            _ => self.to_hash_set() == other.to_hash_set(),
+        }
+    }
+}
+
+impl AnswerSet {
+    /// Collect the answers of a single person, any whitespace is
+    /// ignored.
+    fn from_person(answers: &str) -> AnswerSet {
+        let mut set = AnswerSet::default();
+        for answer in answers.chars().filter(|c| !c.is_whitespace()) {
+            set.insert(answer);
+        }
+        set
+    }
+
+    fn insert(&mut self, answer: char) {
+        match self {
+            AnswerSet::Ascii(bits) if answer.is_ascii() => *bits |= 1 << answer as u32,
+            AnswerSet::Ascii(_) => {
+                let mut set = self.to_hash_set();
+                set.insert(answer);
+                *self = AnswerSet::Any(set);
+            }
+            AnswerSet::Any(set) => {
+                set.insert(answer);
+            }
+        }
+    }
+
+    fn from_questions(questions: impl IntoIterator<Item = char>) -> AnswerSet {
+        let mut set = AnswerSet::default();
+        for q in questions {
+            set.insert(q);
+        }
+        set
+    }
+
+    /// Answered questions in ascending order.
+    fn questions(&self) -> Vec<char> {
+        let mut questions: Vec<_> = self.to_hash_set().into_iter().collect();
+        questions.sort_unstable();
+        questions
+    }
+
+    fn to_hash_set(&self) -> HashSet<char> {
+        match self {
+            AnswerSet::Ascii(bits) => (0u8..128)
+                .filter(|&c| bits & (1 << c) != 0)
+                .map(char::from)
+                .collect(),
+            AnswerSet::Any(set) => set.clone(),
+        }
+    }
+
+    /// Questions answered by anyone in `self` or `other`.
+    fn union(&self, other: &AnswerSet) -> AnswerSet {
+        match (self, other) {
+            (AnswerSet::Ascii(b1), AnswerSet::Ascii(b2)) => AnswerSet::Ascii(b1 | b2),
+            _ => AnswerSet::Any(&self.to_hash_set() | &other.to_hash_set()),
+        }
+    }
+
+    /// Questions answered by both `self` and `other`.
+    fn intersection(&self, other: &AnswerSet) -> AnswerSet {
+        match (self, other) {
+            (AnswerSet::Ascii(b1), AnswerSet::Ascii(b2)) => AnswerSet::Ascii(b1 & b2),
+            _ => AnswerSet::Any(&self.to_hash_set() & &other.to_hash_set()),
+        }
+    }
+
+    /// Questions answered in `self` but not in `other`.
+    fn difference(&self, other: &AnswerSet) -> AnswerSet {
+        match (self, other) {
+            (AnswerSet::Ascii(b1), AnswerSet::Ascii(b2)) => AnswerSet::Ascii(b1 & !b2),
+            _ => AnswerSet::Any(&self.to_hash_set() - &other.to_hash_set()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            AnswerSet::Ascii(bits) => popcount(*bits),
+            AnswerSet::Any(set) => set.len(),
+        }
+    }
+}
+
+/// Number of set bits in `bits`.
+///
+/// `count_ones` is only lowered to the hardware popcount instruction if
+/// the build target enables it, so on x86_64 the CPU is checked at
+/// runtime and the portable `count_ones` is the fallback.
+fn popcount(bits: u128) -> usize {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("popcnt") {
+            // Safe as the CPU supports `popcnt`.
+            return unsafe { popcount_hw(bits) };
+        }
+    }
+    bits.count_ones() as usize
+}
 
 #[cfg(target_arch = "x86_64")]
-fn popcnt32(x: u32) -> u32 {
-    unsafe { core::arch::x86_64::_popcnt32(x as i32) as u32 }
+#[target_feature(enable = "popcnt")]
+unsafe fn popcount_hw(bits: u128) -> usize {
+    bits.count_ones() as usize
 }
 
-#[cfg(not(target_arch = "x86_64"))]
-fn popcnt32(x: u32) -> u32 {
-    (0..32).map(|shamt| (x >> shamt) & 0b1).sum()
+/// Answer sets of all persons of a group, one person per line.
+fn parse_group(group_answers: &str) -> Vec<AnswerSet> {
+    group_answers.lines().map(AnswerSet::from_person).collect()
 }
 
-fn challenge1() -> io::Result<u32> {
-    let mut total = 0;
-    for group_answers in aoc20::read_input_to_string("day06").split("\n\n") {
-        let mut anyone_yes = 0;
-        for answer in group_answers.chars() {
-            match answer {
-                'a'..='z' => {
-                    let shamt = |c: char| c as u32 - 'a' as u32;
-                    anyone_yes |= 1 << shamt(answer);
+/// Number of persons that answered each question with yes.
+fn histogram(persons: &[AnswerSet]) -> BTreeMap<char, usize> {
+    let mut hist = BTreeMap::new();
+    for q in persons.iter().flat_map(AnswerSet::questions) {
+        *hist.entry(q).or_insert(0) += 1;
+    }
+    hist
+}
+
+/// Query selecting questions of a group.
+///
+/// Syntax:
+///  - `any`: answered by anyone.
+///  - `all`: answered by everyone.
+///  - `>=K`: answered by at least K persons.
+///  - `==K`: answered by exactly K persons.
+///  - `<=K`: answered by at least one and at most K persons.
+///  - `a | b`: union.
+///  - `a & b`: intersection.
+///  - `a - b`: difference.
+///  - `( a )`: grouping.
+///
+/// Binary operators have the same precedence and are evaluated
+/// left-to-right, eg `>=2 - all` selects the questions answered by
+/// more than one but not by all persons.
+#[derive(Debug, PartialEq)]
+enum Query {
+    Any,
+    All,
+    AtLeast(usize),
+    Exactly(usize),
+    AtMost(usize),
+    Union(Box<Query>, Box<Query>),
+    Intersection(Box<Query>, Box<Query>),
+    Difference(Box<Query>, Box<Query>),
+}
+
+impl Query {
+    /// Questions of the group `persons` selected by the query.
+    fn eval(&self, persons: &[AnswerSet]) -> AnswerSet {
+        self.eval_histogram(&histogram(persons), persons.len())
+    }
+
+    fn eval_histogram(&self, hist: &BTreeMap<char, usize>, persons: usize) -> AnswerSet {
+        let select = |f: &dyn Fn(usize) -> bool| {
+            AnswerSet::from_questions(hist.iter().filter(|(_, &n)| f(n)).map(|(&q, _)| q))
+        };
+
+        match self {
+            Query::Any => select(&|_| true),
+            Query::All => select(&|n| n == persons),
+            Query::AtLeast(k) => select(&|n| n >= *k),
+            Query::Exactly(k) => select(&|n| n == *k),
+            Query::AtMost(k) => select(&|n| n <= *k),
+            Query::Union(a, b) => a
+                .eval_histogram(hist, persons)
+                .union(&b.eval_histogram(hist, persons)),
+            Query::Intersection(a, b) => a
+                .eval_histogram(hist, persons)
+                .intersection(&b.eval_histogram(hist, persons)),
+            Query::Difference(a, b) => a
+                .eval_histogram(hist, persons)
+                .difference(&b.eval_histogram(hist, persons)),
+        }
+    }
+
+    fn parse_expr(input: &mut Peekable<Chars>) -> io::Result<Query> {
+        let mut lhs = Query::parse_term(input)?;
+        while let Some(op) = next_non_ws(input, |c| matches!(c, '|' | '&' | '-')) {
+            let rhs = Box::new(Query::parse_term(input)?);
+            lhs = match op {
+                '|' => Query::Union(Box::new(lhs), rhs),
+                '&' => Query::Intersection(Box::new(lhs), rhs),
+                _ => Query::Difference(Box::new(lhs), rhs),
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn parse_term(input: &mut Peekable<Chars>) -> io::Result<Query> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg);
+
+        match next_non_ws(input, |_| true) {
+            Some('(') => {
+                let q = Query::parse_expr(input)?;
+                next_non_ws(input, |c| c == ')').ok_or_else(|| invalid("Expected ')'"))?;
+                Ok(q)
+            }
+            Some(c @ '>') | Some(c @ '=') | Some(c @ '<') => {
+                input
+                    .next_if_eq(&'=')
+                    .ok_or_else(|| invalid("Expected '>=', '==' or '<='"))?;
+                let mut k = String::new();
+                while let Some(d) = input.next_if(char::is_ascii_digit) {
+                    k.push(d);
                 }
-                '\n' => {} // new person -> ignore
-                _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "Invalid answer must be in range a-z",
-                    ))
+                let k = k
+                    .parse()
+                    .map_err(|_| invalid("Expected number after comparison"))?;
+                Ok(match c {
+                    '>' => Query::AtLeast(k),
+                    '=' => Query::Exactly(k),
+                    _ => Query::AtMost(k),
+                })
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let mut word = String::from(c);
+                while let Some(c) = input.next_if(char::is_ascii_alphabetic) {
+                    word.push(c);
+                }
+                match word.as_str() {
+                    "any" => Ok(Query::Any),
+                    "all" => Ok(Query::All),
+                    _ => Err(invalid("Unknown query keyword")),
                 }
             }
+            _ => Err(invalid("Expected query term")),
         }
-        total += popcnt32(anyone_yes);
     }
-    Ok(total)
 }
 
-fn challenge2() -> io::Result<u32> {
+/// Skip whitespace and consume the next char if it matches `pred`.
+fn next_non_ws(input: &mut Peekable<Chars>, pred: impl Fn(char) -> bool) -> Option<char> {
+    while input.next_if(|c| c.is_whitespace()).is_some() {}
+    input.next_if(|&c| pred(c))
+}
+
+impl FromStr for Query {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut input = s.chars().peekable();
+        let q = Query::parse_expr(&mut input)?;
+        if next_non_ws(&mut input, |_| true).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Trailing input after query",
+            ));
+        }
+        Ok(q)
+    }
+}
+
+/// Sum over all groups of the number of questions selected by `query`.
+fn count_query(query: &Query) -> usize {
+    aoc20::read_input_to_string("day06")
+        .split("\n\n")
+        .map(|group_answers| query.eval(&parse_group(group_answers)).len())
+        .sum()
+}
+
+fn challenge1() -> io::Result<usize> {
     let mut total = 0;
     for group_answers in aoc20::read_input_to_string("day06").split("\n\n") {
-        let everyone_yes: io::Result<u32> = group_answers
-            .lines()
-            .map(|person_answers| {
-                person_answers
-                    .chars()
-                    .try_fold(0u32, |person_yes, answer| match answer {
-                        'a'..='z' => {
-                            let shamt = |c: char| c as u32 - 'a' as u32;
-                            Ok(person_yes | (1 << shamt(answer)))
-                        }
-                        _ => Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "Invalid answer must be in range a-z",
-                        )),
-                    })
-            })
-            .try_fold(std::u32::MAX, |everyone_yes, person_yes| {
-                Ok(everyone_yes & person_yes?)
+        let anyone_yes = parse_group(group_answers)
+            .iter()
+            .fold(AnswerSet::default(), |anyone_yes, person_yes| {
+                anyone_yes.union(person_yes)
             });
-        total += popcnt32(everyone_yes?);
+        total += anyone_yes.len();
+    }
+    Ok(total)
+}
+
+fn challenge2() -> io::Result<usize> {
+    let mut total = 0;
+    for group_answers in aoc20::read_input_to_string("day06").split("\n\n") {
+        let everyone_yes = parse_group(group_answers)
+            .into_iter()
+            .reduce(|everyone_yes, person_yes| everyone_yes.intersection(&person_yes))
+            .unwrap_or_default();
+        total += everyone_yes.len();
     }
     Ok(total)
 }
 
 fn main() -> io::Result<()> {
-    println!("{:?}", challenge1()?);
-    println!("{:?}", challenge2()?);
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => {
+            println!("{:?}", challenge1()?);
+            println!("{:?}", challenge2()?);
+        }
+        [cmd, query] if cmd == "query" => {
+            println!("{}", count_query(&query.parse()?));
+        }
+        [cmd] if cmd == "histogram" => {
+            let mut total = BTreeMap::new();
+            for (idx, group_answers) in aoc20::read_input_to_string("day06")
+                .split("\n\n")
+                .enumerate()
+            {
+                let hist = histogram(&parse_group(group_answers));
+                let hist_str: Vec<_> = hist.iter().map(|(q, n)| format!("{}:{}", q, n)).collect();
+                println!("group {}: {}", idx + 1, hist_str.join(" "));
+                for (q, n) in hist {
+                    *total.entry(q).or_insert(0) += n;
+                }
+            }
+            for (q, n) in total {
+                println!("{} {}", q, n);
+            }
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Usage: day06 [query <expr> | histogram]",
+            ))
+        }
+    }
     Ok(())
 }
 
@@ -82,4 +365,57 @@ mod tests {
         assert_eq!(challenge2()?, 3427);
         Ok(())
     }
+
+    #[test]
+    fn check_query() -> io::Result<()> {
+        let group = parse_group("abc\nabd\nae\nb");
+        let eval =
+            |q: &str| -> io::Result<Vec<char>> { Ok(q.parse::<Query>()?.eval(&group).questions()) };
+
+        assert_eq!(
+            histogram(&group).into_iter().collect::<Vec<_>>(),
+            vec![('a', 3), ('b', 3), ('c', 1), ('d', 1), ('e', 1)]
+        );
+        assert_eq!(eval("any")?, vec!['a', 'b', 'c', 'd', 'e']);
+        assert_eq!(eval("all")?, vec![]);
+        assert_eq!(eval(">=3")?, vec!['a', 'b']);
+        assert_eq!(eval("==1")?, vec!['c', 'd', 'e']);
+        assert_eq!(eval("<=1 | >=3")?, vec!['a', 'b', 'c', 'd', 'e']);
+        assert_eq!(eval("any - (==1 & <=2)")?, vec!['a', 'b']);
+        assert_eq!(eval("any - ==1 & <=2")?, vec![]);
+
+        assert!("any |".parse::<Query>().is_err());
+        assert!("(any".parse::<Query>().is_err());
+        assert!("> 2".parse::<Query>().is_err());
+        assert!("some".parse::<Query>().is_err());
+        assert!("any all".parse::<Query>().is_err());
+
+        assert_eq!(count_query(&"any".parse()?), challenge1()?);
+        assert_eq!(count_query(&"all".parse()?), challenge2()?);
+        Ok(())
+    }
+
+    #[test]
+    fn check_answer_set() {
+        let p1 = AnswerSet::from_person("aB3~");
+        let p2 = AnswerSet::from_person("B3x");
+        assert_eq!(
+            p1,
+            AnswerSet::Ascii(
+                (1 << 'a' as u32) | (1 << 'B' as u32) | (1 << '3' as u32) | (1 << '~' as u32)
+            )
+        );
+        assert_eq!(p1.union(&p2).len(), 5);
+        assert_eq!(p1.intersection(&p2).len(), 2);
+
+        let p3 = AnswerSet::from_person("Bλ→");
+        assert!(matches!(p3, AnswerSet::Any(_)));
+        assert_eq!(p3.len(), 3);
+        assert_eq!(p1.union(&p3).len(), 6);
+        assert_eq!(p1.intersection(&p3), AnswerSet::from_person("B"));
+
+        for bits in [0, 1, u128::MAX, 1 << 127 | 1 << 64 | 5] {
+            assert_eq!(popcount(bits), bits.count_ones() as usize);
+        }
+    }
 }
@@ -1,124 +1,625 @@
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::io;
+use std::fmt;
+use std::io::{self, Write};
 
-struct Node<'a> {
-    col: &'a str,
+/// Interned bag color, index into `BagGraph::colors`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct ColorId(usize);
+
+/// Edge in the bag graph to the bag with color `col`, where `cnt` is
+/// the number of inner bags.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Edge {
+    col: ColorId,
     cnt: usize,
 }
 
-/// Challenge1
-///
-/// Allow to map inner bags to outer bags which allows for easy
-/// upwards travesal of the dependency graph and hence counting how
-/// many bags can contain a "shiny gold" bag.
-type Inner2Outer<'a> = HashMap<&'a str, HashSet<&'a str>>;
+/// Memoization state of a value computed per bag from its inner bags.
+#[derive(Clone, Copy)]
+enum Memo<T> {
+    Unknown,
+    /// Computation started but not yet finished, seeing it again means
+    /// the bag contains itself.
+    Pending,
+    Known(T),
+}
 
-/// Challenge2
-///
-/// Map outer bags to number of inner bags needed which allows for
-/// easy downwards travesal to compute the total number of inner bags.
-type Outer2Inner<'a> = HashMap<&'a str, Vec<Node<'a>>>;
+impl<T: Copy> Memo<T> {
+    /// Value of a bag computed before.
+    fn known(&self) -> T {
+        match self {
+            Memo::Known(n) => *n,
+            _ => unreachable!("Bag value not computed yet"),
+        }
+    }
+}
+
+/// DFS state of a bag in `BagGraph::find_cycles`.
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    New,
+    Active,
+    Done,
+}
+
+/// Inconsistency in the bag rules.
+#[derive(Debug, PartialEq)]
+enum RuleError {
+    /// Bags forming a cycle, each bag contains the next one and the
+    /// last bag contains the first one.
+    Cycle(Vec<String>),
+    /// Bag color used as inner bag but without rule of its own.
+    Undefined(String),
+    /// Additional rule for a bag color on 1-based `line`, the rule is
+    /// ignored.
+    Duplicate { col: String, line: usize },
+    /// Bag which neither contains nor is contained in the root bag.
+    Unreachable(String),
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuleError::Cycle(cols) => write!(f, "Cycle: {} -> {}", cols.join(" -> "), cols[0]),
+            RuleError::Undefined(col) => write!(f, "Undefined bag: {}", col),
+            RuleError::Duplicate { col, line } => {
+                write!(f, "Duplicate rule for bag {} on line {}", col, line)
+            }
+            RuleError::Unreachable(col) => write!(f, "Unreachable bag: {}", col),
+        }
+    }
+}
+
+/// Part of the bag graph to export with `BagGraph::write_dot`.
+#[derive(Clone, Copy)]
+enum Scope {
+    All,
+    /// Bag and all bags that contain it.
+    Ancestors(ColorId),
+    /// Bag and all bags inside it.
+    Descendants(ColorId),
+}
+
+/// Parser for a single rule line, holding the compiled patterns such
+/// that they are only compiled once per rule file.
+struct RuleParser {
+    outer: regex::Regex,
+    inner: regex::Regex,
+}
+
+impl RuleParser {
+    fn new() -> RuleParser {
+        RuleParser {
+            outer: regex::Regex::new(r"([a-z ]+) bags contain").unwrap(),
+            inner: regex::Regex::new(r"(\d+) ([a-z ]+) bags?[,.]?").unwrap(),
+        }
+    }
 
-fn parse_line<'a>(line: &'a str) -> io::Result<(&'a str, Vec<Node<'a>>)> {
-    // Potential improvement, don't compile the regex every time.
-    let outer_parser = regex::Regex::new(r"([a-z ]+) bags contain").unwrap();
-    let inner_parser = regex::Regex::new(r"(\d+) ([a-z ]+) bags?[,.]?").unwrap();
+    /// Parse rule line into outer bag color and inner bags as
+    /// `(color, count)`.
+    fn parse_line<'a>(&self, line: &'a str) -> io::Result<(&'a str, Vec<(&'a str, usize)>)> {
+        let outer = match self.outer.captures(line) {
+            Some(c) => c.get(1).expect("match outer_parser").as_str(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Malformed input line encountered",
+                ))
+            }
+        };
 
-    let outer = match outer_parser.captures(line) {
-        Some(c) => c.get(1).expect("match outer_parser").as_str(),
-        None => {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Malformed input line encountered",
-            ))
+        let mut inner = Vec::new();
+        for cap in self.inner.captures_iter(line) {
+            let col = cap.get(2).expect("match inner_parser").as_str();
+            let cnt = cap[1]
+                .parse::<usize>()
+                .expect("must be a number as specified in inner_parse");
+            inner.push((col, cnt));
         }
-    };
 
-    let mut inner = Vec::new();
-    for cap in inner_parser.captures_iter(line) {
-        let col = cap.get(2).expect("match inner_parser").as_str();
-        let cnt = cap[1]
-            .parse::<usize>()
-            .expect("must be a number as specified in inner_parse");
-        inner.push(Node { col, cnt });
+        Ok((outer, inner))
     }
+}
 
-    Ok((outer, inner))
+/// Graph of the bag rules.
+///
+/// Colors are interned into `ColorId`s and the graph keeps both
+/// adjacency directions, outer bags to inner bags for downwards
+/// traversal and inner bags to outer bags for upwards traversal.
+#[derive(Default)]
+struct BagGraph {
+    colors: Vec<String>,
+    ids: HashMap<String, ColorId>,
+    /// Outer bag to the inner bags it must contain.
+    inner: Vec<Vec<Edge>>,
+    /// Inner bag to the outer bags that contain it.
+    outer: Vec<Vec<Edge>>,
+    /// Whether there is a rule for the bag.
+    defined: Vec<bool>,
+    /// Ignored additional rules with their 1-based line number.
+    duplicates: Vec<(ColorId, usize)>,
 }
 
-fn challenge1() -> io::Result<usize> {
-    let input = aoc20::read_input_to_string("day07");
+impl BagGraph {
+    fn parse(input: &str) -> io::Result<BagGraph> {
+        let parser = RuleParser::new();
+        let mut graph = BagGraph::default();
+
+        for (idx, line) in input.lines().enumerate() {
+            let (outer, inner) = parser.parse_line(line)?;
+            let outer = graph.intern(outer);
+            if graph.defined[outer.0] {
+                graph.duplicates.push((outer, idx + 1));
+                continue;
+            }
+            graph.defined[outer.0] = true;
+
+            for (col, cnt) in inner {
+                let inner = graph.intern(col);
+                graph.inner[outer.0].push(Edge { col: inner, cnt });
+                graph.outer[inner.0].push(Edge { col: outer, cnt });
+            }
+        }
+
+        Ok(graph)
+    }
+
+    fn intern(&mut self, col: &str) -> ColorId {
+        if let Some(id) = self.ids.get(col) {
+            return *id;
+        }
+
+        let id = ColorId(self.colors.len());
+        self.colors.push(String::from(col));
+        self.ids.insert(String::from(col), id);
+        self.inner.push(Vec::new());
+        self.outer.push(Vec::new());
+        self.defined.push(false);
+        id
+    }
+
+    /// Check the rules for cycles, undefined bags and duplicate rules.
+    /// With a `root` bag, bags which neither contain nor are contained
+    /// in it are reported as unreachable.
+    ///
+    /// Returns all inconsistencies found instead of stopping at the
+    /// first one.
+    fn validate(&self, root: Option<ColorId>) -> Result<(), Vec<RuleError>> {
+        let mut errors = Vec::new();
+
+        for (col, line) in &self.duplicates {
+            errors.push(RuleError::Duplicate {
+                col: String::from(self.color(*col)),
+                line: *line,
+            });
+        }
+
+        for (id, defined) in self.defined.iter().enumerate() {
+            if !defined {
+                errors.push(RuleError::Undefined(String::from(self.color(ColorId(id)))));
+            }
+        }
+
+        let mut state = vec![Visit::New; self.colors.len()];
+        for id in 0..self.colors.len() {
+            if state[id] == Visit::New {
+                self.find_cycles(ColorId(id), &mut state, &mut errors);
+            }
+        }
+
+        if let Some(root) = root {
+            for bag in self.unreachable(root) {
+                errors.push(RuleError::Unreachable(String::from(self.color(bag))));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// DFS from `col` along the inner bags, every edge back to a bag on
+    /// the current path closes a cycle.
+    ///
+    /// The path is kept on an explicit stack together with the next edge
+    /// of each bag, so deep rule chains do not overflow the call stack.
+    fn find_cycles(&self, col: ColorId, state: &mut [Visit], errors: &mut Vec<RuleError>) {
+        state[col.0] = Visit::Active;
+        let mut path = vec![(col, 0)];
+
+        while let Some((bag, next)) = path.last().copied() {
+            let Some(edge) = self.inner[bag.0].get(next) else {
+                path.pop();
+                state[bag.0] = Visit::Done;
+                continue;
+            };
+            path.last_mut().expect("bag on path").1 += 1;
+
+            match state[edge.col.0] {
+                Visit::New => {
+                    state[edge.col.0] = Visit::Active;
+                    path.push((edge.col, 0));
+                }
+                Visit::Active => {
+                    let start = path
+                        .iter()
+                        .position(|(c, _)| *c == edge.col)
+                        .expect("on path");
+                    let cycle = path[start..]
+                        .iter()
+                        .map(|(c, _)| String::from(self.color(*c)));
+                    errors.push(RuleError::Cycle(cycle.collect()));
+                }
+                Visit::Done => {}
+            }
+        }
+    }
+
+    /// Mark all bags reachable from `col` following the edges of
+    /// `adjacent`, `col` itself is only marked if part of a cycle.
+    fn reachable(&self, col: ColorId, adjacent: &[Vec<Edge>]) -> Vec<bool> {
+        let mut reachable = vec![false; self.colors.len()];
+        let mut queue = vec![col];
+        while let Some(bag) = queue.pop() {
+            for edge in &adjacent[bag.0] {
+                if !reachable[edge.col.0] {
+                    reachable[edge.col.0] = true;
+                    queue.push(edge.col);
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Bags which neither contain nor are contained in a bag of color
+    /// `col`.
+    fn unreachable(&self, col: ColorId) -> Vec<ColorId> {
+        let descendants = self.reachable(col, &self.inner);
+        let ancestors = self.reachable(col, &self.outer);
+
+        (0..self.colors.len())
+            .filter(|&id| id != col.0 && !descendants[id] && !ancestors[id])
+            .map(ColorId)
+            .collect()
+    }
+
+    /// Write the bags in `scope` in GraphViz DOT format, with edges
+    /// from outer to inner bags labeled with the bag count.
+    ///
+    /// The `highlight` bag is drawn filled.
+    fn write_dot(
+        &self,
+        w: &mut impl Write,
+        scope: Scope,
+        highlight: Option<ColorId>,
+    ) -> io::Result<()> {
+        let included = match scope {
+            Scope::All => vec![true; self.colors.len()],
+            Scope::Ancestors(col) | Scope::Descendants(col) => {
+                let adjacent = match scope {
+                    Scope::Ancestors(_) => &self.outer,
+                    _ => &self.inner,
+                };
+                let mut included = self.reachable(col, adjacent);
+                included[col.0] = true;
+                included
+            }
+        };
+        let quote = |id: ColorId| format!("\"{}\"", self.color(id).replace('"', "\\\""));
+
+        writeln!(w, "digraph bags {{")?;
+        for id in (0..self.colors.len())
+            .filter(|&id| included[id])
+            .map(ColorId)
+        {
+            if Some(id) == highlight {
+                writeln!(w, "    {} [style=filled, fillcolor=gold];", quote(id))?;
+            } else {
+                writeln!(w, "    {};", quote(id))?;
+            }
+        }
+        for id in (0..self.colors.len())
+            .filter(|&id| included[id])
+            .map(ColorId)
+        {
+            for edge in self.inner[id.0].iter().filter(|e| included[e.col.0]) {
+                writeln!(
+                    w,
+                    "    {} -> {} [label=\"{}\"];",
+                    quote(id),
+                    quote(edge.col),
+                    edge.cnt
+                )?;
+            }
+        }
+        writeln!(w, "}}")
+    }
+
+    /// Total number of bags required inside a bag of color `col`.
+    ///
+    /// Inner bag counts are memoized per color, hence the run time is
+    /// linear in the number of rules instead of the number of bags.
+    fn count_inner(&self, col: ColorId) -> io::Result<u128> {
+        self.count_inner_memo(col, &mut vec![Memo::Unknown; self.colors.len()])
+    }
+
+    fn count_inner_memo(&self, col: ColorId, memo: &mut [Memo<u128>]) -> io::Result<u128> {
+        self.memoized(col, memo, |bag, memo| {
+            let mut total = 0u128;
+            for edge in &self.inner[bag.0] {
+                // Each inner bag counts itself plus all bags inside it.
+                let per_bag = memo[edge.col.0].known().checked_add(1);
+                total = per_bag
+                    .and_then(|n| n.checked_mul(edge.cnt as u128))
+                    .and_then(|n| n.checked_add(total))
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Bag count overflow in bag {}", self.colors[bag.0]),
+                        )
+                    })?;
+            }
+            Ok(total)
+        })
+    }
 
-    // Compute graph of inner bags to outer bags.
-    let mut graph = Inner2Outer::new();
-    for line in input.lines() {
-        let (outer, inner) = parse_line(line)?;
-        for Node { col, .. } in inner {
-            graph.entry(col).or_insert(HashSet::new()).insert(outer);
+    /// Compute `value` for `col` and all bags inside it which are not
+    /// yet in `memo`.
+    ///
+    /// Bags are visited in post-order on an explicit stack, so `value`
+    /// finds the values of all inner bags of a bag in `memo` and deep
+    /// nesting does not overflow the call stack.
+    fn memoized<T: Copy>(
+        &self,
+        col: ColorId,
+        memo: &mut [Memo<T>],
+        value: impl Fn(ColorId, &[Memo<T>]) -> io::Result<T>,
+    ) -> io::Result<T> {
+        match memo[col.0] {
+            Memo::Known(n) => return Ok(n),
+            Memo::Pending => return Err(self.cycle_error(col)),
+            Memo::Unknown => memo[col.0] = Memo::Pending,
         }
+
+        let mut stack = vec![(col, 0)];
+        while let Some((bag, next)) = stack.last().copied() {
+            let Some(edge) = self.inner[bag.0].get(next) else {
+                memo[bag.0] = Memo::Known(value(bag, memo)?);
+                stack.pop();
+                continue;
+            };
+            stack.last_mut().expect("bag on stack").1 += 1;
+
+            match memo[edge.col.0] {
+                Memo::Known(_) => {}
+                Memo::Pending => return Err(self.cycle_error(edge.col)),
+                Memo::Unknown => {
+                    memo[edge.col.0] = Memo::Pending;
+                    stack.push((edge.col, 0));
+                }
+            }
+        }
+
+        Ok(memo[col.0].known())
+    }
+
+    fn cycle_error(&self, col: ColorId) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Bag {} contains itself", self.color(col)),
+        )
     }
 
+    /// Bag with the most bags inside it, together with that number.
+    fn largest(&self) -> io::Result<Option<(ColorId, u128)>> {
+        let mut memo = vec![Memo::Unknown; self.colors.len()];
+        let mut largest = None;
+        for id in (0..self.colors.len()).map(ColorId) {
+            let n = self.count_inner_memo(id, &mut memo)?;
+            if largest.is_none_or(|(_, max)| n > max) {
+                largest = Some((id, n));
+            }
+        }
+        Ok(largest)
+    }
+
+    /// Nesting depth of a bag of color `col`, a bag without inner bags
+    /// has depth 0.
+    fn depth(&self, col: ColorId) -> io::Result<usize> {
+        self.depth_memo(col, &mut vec![Memo::Unknown; self.colors.len()])
+    }
+
+    fn depth_memo(&self, col: ColorId, memo: &mut [Memo<usize>]) -> io::Result<usize> {
+        self.memoized(col, memo, |bag, memo| {
+            Ok(self.inner[bag.0]
+                .iter()
+                .map(|edge| memo[edge.col.0].known() + 1)
+                .max()
+                .unwrap_or(0))
+        })
+    }
+
+    /// Maximum nesting depth over all bags.
+    fn max_depth(&self) -> io::Result<usize> {
+        let mut memo = vec![Memo::Unknown; self.colors.len()];
+        let mut max = 0;
+        for id in (0..self.colors.len()).map(ColorId) {
+            max = max.max(self.depth_memo(id, &mut memo)?);
+        }
+        Ok(max)
+    }
+
+    /// All containment paths from a bag of color `from` down to a bag
+    /// of color `to`, both included in the path.
+    ///
+    /// Bags are not repeated within a path, hence the search terminates
+    /// even for cyclic rules.
+    fn paths(&self, from: ColorId, to: ColorId) -> Vec<Vec<ColorId>> {
+        let mut paths = Vec::new();
+        self.paths_rec(to, &mut vec![from], &mut paths);
+        paths
+    }
+
+    fn paths_rec(&self, to: ColorId, path: &mut Vec<ColorId>, paths: &mut Vec<Vec<ColorId>>) {
+        let bag = *path.last().expect("path starts with from bag");
+        if bag == to {
+            paths.push(path.clone());
+            return;
+        }
+
+        for edge in &self.inner[bag.0] {
+            if !path.contains(&edge.col) {
+                path.push(edge.col);
+                self.paths_rec(to, path, paths);
+                path.pop();
+            }
+        }
+    }
+
+    /// Bags which can (eventually) hold both a bag of color `a` and a
+    /// bag of color `b`.
+    fn holding_both(&self, a: ColorId, b: ColorId) -> Vec<ColorId> {
+        let holding_a = self.reachable(a, &self.outer);
+        let holding_b = self.reachable(b, &self.outer);
+
+        (0..self.colors.len())
+            .filter(|&id| holding_a[id] && holding_b[id])
+            .map(ColorId)
+            .collect()
+    }
+
+    fn color(&self, id: ColorId) -> &str {
+        &self.colors[id.0]
+    }
+
+    fn id(&self, col: &str) -> io::Result<ColorId> {
+        self.ids.get(col).copied().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Unknown bag color: {}", col),
+            )
+        })
+    }
+}
+
+/// Parse the rules of the input and check them for consistency.
+fn parse_graph() -> io::Result<BagGraph> {
+    let graph = BagGraph::parse(&aoc20::read_input_to_string("day07"))?;
+    match graph.validate(None) {
+        Ok(()) => Ok(graph),
+        Err(errors) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            errors
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )),
+    }
+}
+
+fn challenge1(graph: &BagGraph) -> io::Result<usize> {
+    let shiny_gold = graph.id("shiny gold")?;
+
     // Current working set of bags to inspect and detect if they can
     // be contained in other bags. Fill the queue initially with our
     // "shiny gold" bag and then start looking which bags can contain
     // our bag. We do this until there are no more bags left to
     // inspect.
     let mut queue = VecDeque::new();
-    queue.push_back("shiny gold");
+    queue.push_back(shiny_gold);
 
     let mut visited = HashSet::new();
     while let Some(bag) = queue.pop_front() {
-        if visited.contains(bag) {
+        if visited.contains(&bag) {
             continue;
         }
 
         // We only count for bags that can contain the "shiny gold"
         // bag, not our bag itself.
-        if bag != "shiny gold" {
+        if bag != shiny_gold {
             visited.insert(bag);
         }
 
-        if let Some(bags) = graph.get(bag) {
-            for bag in bags {
-                queue.push_back(bag);
-            }
+        for edge in &graph.outer[bag.0] {
+            queue.push_back(edge.col);
         }
     }
 
     Ok(visited.len())
 }
 
-fn challenge2() -> io::Result<usize> {
-    let input = aoc20::read_input_to_string("day07");
+fn challenge2(graph: &BagGraph) -> io::Result<u128> {
+    graph.count_inner(graph.id("shiny gold")?)
+}
 
-    // Compute graph of outer bags to inner bags.
-    let mut graph = Outer2Inner::new();
-    for line in input.lines() {
-        let (outer, inner) = parse_line(line)?;
-        graph.insert(outer, inner);
-    }
+fn usage() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Usage: day07 [count <color> | unreachable <color> | \
+         dot [[ancestors | descendants] <color>] | paths <from> <to> | \
+         depth [<color>] | largest | holds <color> <color> | validate <color>]",
+    )
+}
 
-    // Working set of inner bags that need to be packed.
-    // We start of with our "shiny gold" one.
-    let mut queue = VecDeque::new();
-    queue.push_back("shiny gold");
+fn main() -> io::Result<()> {
+    let graph = parse_graph()?;
 
-    let mut inner_bags = 0;
-    while let Some(bag) = queue.pop_front() {
-        for node in &graph[bag] {
-            inner_bags += node.cnt;
-            for _ in 0..node.cnt {
-                queue.push_back(node.col);
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => {
+            println!("{}", challenge1(&graph)?);
+            println!("{}", challenge2(&graph)?);
+        }
+        [cmd, col] if cmd == "count" => println!("{}", graph.count_inner(graph.id(col)?)?),
+        [cmd] if cmd == "dot" => graph.write_dot(&mut io::stdout(), Scope::All, None)?,
+        [cmd, col] if cmd == "dot" => {
+            let col = graph.id(col)?;
+            graph.write_dot(&mut io::stdout(), Scope::All, Some(col))?
+        }
+        [cmd, scope, col] if cmd == "dot" => {
+            let col = graph.id(col)?;
+            let scope = match scope.as_str() {
+                "ancestors" => Scope::Ancestors(col),
+                "descendants" => Scope::Descendants(col),
+                _ => return Err(usage()),
+            };
+            graph.write_dot(&mut io::stdout(), scope, Some(col))?
+        }
+        [cmd, from, to] if cmd == "paths" => {
+            for path in graph.paths(graph.id(from)?, graph.id(to)?) {
+                let path: Vec<_> = path.iter().map(|c| graph.color(*c)).collect();
+                println!("{}", path.join(" -> "));
             }
         }
+        [cmd] if cmd == "depth" => println!("{}", graph.max_depth()?),
+        [cmd, col] if cmd == "depth" => println!("{}", graph.depth(graph.id(col)?)?),
+        [cmd] if cmd == "largest" => {
+            if let Some((col, n)) = graph.largest()? {
+                println!("{} {}", graph.color(col), n);
+            }
+        }
+        [cmd, a, b] if cmd == "holds" => {
+            for bag in graph.holding_both(graph.id(a)?, graph.id(b)?) {
+                println!("{}", graph.color(bag));
+            }
+        }
+        [cmd, col] if cmd == "unreachable" => {
+            for bag in graph.unreachable(graph.id(col)?) {
+                println!("{}", graph.color(bag));
+            }
+        }
+        [cmd, col] if cmd == "validate" => {
+            if let Err(errors) = graph.validate(Some(graph.id(col)?)) {
+                for e in errors {
+                    println!("{}", e);
+                }
+            }
+        }
+        _ => return Err(usage()),
     }
-
-    Ok(inner_bags)
-}
-
-fn main() -> io::Result<()> {
-    println!("{}", challenge1()?);
-    println!("{}", challenge2()?);
     Ok(())
 }
 
@@ -128,13 +629,247 @@ mod tests {
 
     #[test]
     fn check_challenge1() -> io::Result<()> {
-        assert_eq!(challenge1()?, 268);
+        let g = parse_graph()?;
+        assert_eq!(challenge1(&g)?, 268);
         Ok(())
     }
 
     #[test]
     fn check_challenge2() -> io::Result<()> {
-        assert_eq!(challenge2()?, 7867);
+        let g = parse_graph()?;
+        assert_eq!(challenge2(&g)?, 7867);
+        Ok(())
+    }
+
+    #[test]
+    fn check_graph() -> io::Result<()> {
+        let g = BagGraph::parse(
+            "light red bags contain 1 bright white bag, 2 muted yellow bags.\n\
+             bright white bags contain 1 shiny gold bag.\n\
+             muted yellow bags contain 2 shiny gold bags, 9 faded blue bags.\n\
+             faded blue bags contain no other bags.",
+        )?;
+
+        let muted_yellow = g.id("muted yellow")?;
+        assert_eq!(g.colors[muted_yellow.0], "muted yellow");
+        let inner: Vec<_> = g.inner[muted_yellow.0]
+            .iter()
+            .map(|e| (g.colors[e.col.0].as_str(), e.cnt))
+            .collect();
+        assert_eq!(inner, vec![("shiny gold", 2), ("faded blue", 9)]);
+
+        let outer: Vec<_> = g.outer[g.id("shiny gold")?.0]
+            .iter()
+            .map(|e| (g.colors[e.col.0].as_str(), e.cnt))
+            .collect();
+        assert_eq!(outer, vec![("bright white", 1), ("muted yellow", 2)]);
+
+        assert!(g.id("dotted black").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_count_inner() -> io::Result<()> {
+        let g = BagGraph::parse(
+            "shiny gold bags contain 2 dark red bags.\n\
+             dark red bags contain 2 dark orange bags.\n\
+             dark orange bags contain 2 dark yellow bags.\n\
+             dark yellow bags contain no other bags.",
+        )?;
+        assert_eq!(g.count_inner(g.id("shiny gold")?)?, 14);
+        assert_eq!(g.count_inner(g.id("dark red")?)?, 6);
+        assert_eq!(g.count_inner(g.id("dark yellow")?)?, 0);
+
+        // 64 levels of 1000 bags each overflow even an u128.
+        let level = |i: usize| format!("level {}", "a".repeat(i + 1));
+        let mut rules = String::new();
+        for i in 0..64 {
+            rules += &format!("{} bags contain 1000 {} bags.\n", level(i), level(i + 1));
+        }
+        let g = BagGraph::parse(&rules)?;
+        assert_eq!(g.count_inner(g.id(&level(62))?)?, 1001000);
+        assert!(g.count_inner(g.id(&level(0))?).is_err());
+
+        let g = BagGraph::parse(
+            "dark red bags contain 2 dark orange bags.\n\
+             dark orange bags contain 1 dark red bag.",
+        )?;
+        assert!(g.count_inner(g.id("dark red")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_dot() -> io::Result<()> {
+        let g = BagGraph::parse(
+            "light red bags contain 1 bright white bag, 2 muted yellow bags.\n\
+             bright white bags contain 1 shiny gold bag.\n\
+             muted yellow bags contain 2 shiny gold bags, 9 faded blue bags.\n\
+             shiny gold bags contain 1 dark olive bag.\n\
+             faded blue bags contain no other bags.\n\
+             dark olive bags contain no other bags.",
+        )?;
+        let shiny_gold = g.id("shiny gold")?;
+
+        let mut dot = Vec::new();
+        g.write_dot(&mut dot, Scope::Ancestors(shiny_gold), Some(shiny_gold))?;
+        assert_eq!(
+            String::from_utf8(dot).unwrap(),
+            "digraph bags {\n\
+            \x20   \"light red\";\n\
+            \x20   \"bright white\";\n\
+            \x20   \"muted yellow\";\n\
+            \x20   \"shiny gold\" [style=filled, fillcolor=gold];\n\
+            \x20   \"light red\" -> \"bright white\" [label=\"1\"];\n\
+            \x20   \"light red\" -> \"muted yellow\" [label=\"2\"];\n\
+            \x20   \"bright white\" -> \"shiny gold\" [label=\"1\"];\n\
+            \x20   \"muted yellow\" -> \"shiny gold\" [label=\"2\"];\n\
+            }\n"
+        );
+
+        let mut dot = Vec::new();
+        g.write_dot(&mut dot, Scope::Descendants(shiny_gold), None)?;
+        assert_eq!(
+            String::from_utf8(dot).unwrap(),
+            "digraph bags {\n\
+            \x20   \"shiny gold\";\n\
+            \x20   \"dark olive\";\n\
+            \x20   \"shiny gold\" -> \"dark olive\" [label=\"1\"];\n\
+            }\n"
+        );
+
+        let mut dot = Vec::new();
+        g.write_dot(&mut dot, Scope::All, None)?;
+        assert_eq!(String::from_utf8(dot).unwrap().lines().count(), 2 + 6 + 6);
+        Ok(())
+    }
+
+    #[test]
+    fn check_queries() -> io::Result<()> {
+        let g = BagGraph::parse(
+            "light red bags contain 1 bright white bag, 2 muted yellow bags.\n\
+             dark orange bags contain 3 bright white bags, 4 muted yellow bags.\n\
+             bright white bags contain 1 shiny gold bag.\n\
+             muted yellow bags contain 2 shiny gold bags, 9 faded blue bags.\n\
+             shiny gold bags contain 1 dark olive bag, 2 vibrant plum bags.\n\
+             dark olive bags contain 3 faded blue bags, 4 dotted black bags.\n\
+             vibrant plum bags contain 5 faded blue bags, 6 dotted black bags.\n\
+             faded blue bags contain no other bags.\n\
+             dotted black bags contain no other bags.",
+        )?;
+        let colors = |ids: &[ColorId]| -> Vec<&str> { ids.iter().map(|c| g.color(*c)).collect() };
+
+        let paths: Vec<_> = g
+            .paths(g.id("light red")?, g.id("dark olive")?)
+            .iter()
+            .map(|p| colors(p).join(" -> "))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                "light red -> bright white -> shiny gold -> dark olive",
+                "light red -> muted yellow -> shiny gold -> dark olive",
+            ]
+        );
+        assert!(g.paths(g.id("dark olive")?, g.id("light red")?).is_empty());
+
+        assert_eq!(g.depth(g.id("shiny gold")?)?, 2);
+        assert_eq!(g.depth(g.id("faded blue")?)?, 0);
+        assert_eq!(g.max_depth()?, 4);
+
+        let (col, n) = g.largest()?.unwrap();
+        assert_eq!((g.color(col), n), ("dark orange", 406));
+
+        let both = g.holding_both(g.id("bright white")?, g.id("muted yellow")?);
+        assert_eq!(colors(&both), vec!["light red", "dark orange"]);
+        let both = g.holding_both(g.id("dark olive")?, g.id("faded blue")?);
+        assert_eq!(
+            colors(&both),
+            vec![
+                "light red",
+                "bright white",
+                "muted yellow",
+                "dark orange",
+                "shiny gold"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_validate() -> io::Result<()> {
+        let g = BagGraph::parse(
+            "light red bags contain 1 bright white bag, 2 muted yellow bags.\n\
+             bright white bags contain 1 shiny gold bag.\n\
+             muted yellow bags contain 2 shiny gold bags, 9 faded blue bags.\n\
+             shiny gold bags contain 1 dark olive bag.\n\
+             dark olive bags contain 3 bright white bags.\n\
+             muted yellow bags contain no other bags.\n\
+             dotted black bags contain no other bags.",
+        )?;
+
+        let errors: Vec<_> = g
+            .validate(None)
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "Duplicate rule for bag muted yellow on line 6",
+                "Undefined bag: faded blue",
+                "Cycle: bright white -> shiny gold -> dark olive -> bright white",
+            ]
+        );
+
+        let unreachable: Vec<_> = g
+            .unreachable(g.id("muted yellow")?)
+            .iter()
+            .map(|c| g.color(*c))
+            .collect();
+        assert_eq!(unreachable, vec!["dotted black"]);
+
+        let errors = g.validate(Some(g.id("muted yellow")?)).unwrap_err();
+        assert_eq!(errors.len(), 4);
+        assert_eq!(errors[3].to_string(), "Unreachable bag: dotted black");
+        Ok(())
+    }
+
+    /// Chain of `len` bags each containing the next one, the last bag
+    /// contains the first one if `cyclic`.
+    ///
+    /// The graph is built directly, parsing that many rules is slow.
+    fn chain(len: usize, cyclic: bool) -> BagGraph {
+        let mut g = BagGraph::default();
+        let ids: Vec<_> = (0..len).map(|i| g.intern(&format!("deep {}", i))).collect();
+        for (i, &id) in ids.iter().enumerate() {
+            g.defined[id.0] = true;
+            let inner = match ids.get(i + 1) {
+                Some(&inner) => inner,
+                None if cyclic => ids[0],
+                None => continue,
+            };
+            g.inner[id.0].push(Edge { col: inner, cnt: 1 });
+            g.outer[inner.0].push(Edge { col: id, cnt: 1 });
+        }
+        g
+    }
+
+    #[test]
+    fn check_deep_chain() -> io::Result<()> {
+        const LEN: usize = 50_000;
+
+        let g = chain(LEN, true);
+        let errors = g.validate(None).unwrap_err();
+        assert!(matches!(&errors[..], [RuleError::Cycle(cols)] if cols.len() == LEN));
+        assert!(g.count_inner(g.id("deep 0")?).is_err());
+        assert!(g.depth(g.id("deep 0")?).is_err());
+
+        let g = chain(LEN, false);
+        assert_eq!(g.validate(None), Ok(()));
+        assert_eq!(g.count_inner(g.id("deep 0")?)?, LEN as u128 - 1);
+        assert_eq!(g.depth(g.id("deep 0")?)?, LEN - 1);
+        assert_eq!(g.max_depth()?, LEN - 1);
         Ok(())
     }
 }
@@ -1,141 +1,1186 @@
-use std::collections::HashSet;
-use std::io;
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::rc::Rc;
+use std::str::FromStr;
+
+/// Instruction of the hand held boot code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Instr {
+    Acc(isize),
+    Jmp(isize),
+    Nop(isize),
+    /// Instruction registered in an `InstrSet`.
+    Ext(&'static InstrDef, Operands),
+}
+
+/// Number of general purpose registers, named `a` to `h`.
+const REGS: usize = 8;
+
+/// Operand of an extension instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+    Reg(usize),
+    Imm(isize),
+}
+
+/// Operands of an extension instruction, unused ones are `None`.
+type Operands = [Option<Operand>; 2];
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(r) => write!(f, "{}", (b'a' + *r as u8) as char),
+            Operand::Imm(n) => write!(f, "{:+}", n),
+        }
+    }
+}
+
+impl FromStr for Operand {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            [r @ b'a'..=b'h'] => Ok(Operand::Reg((r - b'a') as usize)),
+            _ => s
+                .parse()
+                .map(Operand::Imm)
+                .map_err(|_| "OPERAND is neither a register nor a number"),
+        }
+    }
+}
+
+/// Decode `args` as operands, `regs[i]` tells if argument `i` has to be
+/// a register.
+fn decode_operands(args: &[&str], regs: &[bool]) -> Result<Operands, &'static str> {
+    if args.len() != regs.len() {
+        return Err("Wrong number of OPERANDS");
+    }
+
+    let mut ops = [None; 2];
+    for ((op, arg), &reg) in ops.iter_mut().zip(args).zip(regs) {
+        let arg = arg.parse()?;
+        if reg && !matches!(arg, Operand::Reg(_)) {
+            return Err("OPERAND is not a register");
+        }
+        *op = Some(arg);
+    }
+    Ok(ops)
+}
+
+/// Control flow after executing an extension instruction.
+enum Flow {
+    Next,
+    Jump(isize),
+    Halt,
+    /// Waiting for input, the instruction is retried once input is
+    /// available.
+    Block,
+    /// Arithmetic overflow, the instruction has no effect.
+    Overflow,
+}
+
+/// Definition of an additional instruction with its own decoder and
+/// semantics.
+#[derive(Debug)]
+struct InstrDef {
+    mnemonic: &'static str,
+    /// Decode the arguments following the mnemonic.
+    decode: fn(&[&str]) -> Result<Operands, &'static str>,
+    /// Execute the instruction, the `pc` is updated by the hand held.
+    exec: fn(&mut HandHeld, &Operands) -> Flow,
+}
+
+impl PartialEq for InstrDef {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// Instructions used by simulation programs: registers, conditional
+/// jumps, input/output and halt.
+static SIM_INSTRS: [InstrDef; 7] = [
+    InstrDef {
+        mnemonic: "set",
+        decode: |args| decode_operands(args, &[true, false]),
+        exec: |hh, ops| {
+            *hh.reg(ops[0]) = hh.value(ops[1]);
+            Flow::Next
+        },
+    },
+    InstrDef {
+        mnemonic: "add",
+        decode: |args| decode_operands(args, &[true, false]),
+        exec: |hh, ops| {
+            let val = hh.value(ops[1]);
+            let reg = hh.reg(ops[0]);
+            match reg.checked_add(val) {
+                Some(sum) => {
+                    *reg = sum;
+                    Flow::Next
+                }
+                None => Flow::Overflow,
+            }
+        },
+    },
+    InstrDef {
+        mnemonic: "jz",
+        decode: |args| decode_operands(args, &[false, false]),
+        exec: |hh, ops| match hh.value(ops[0]) {
+            0 => Flow::Jump(hh.value(ops[1])),
+            _ => Flow::Next,
+        },
+    },
+    InstrDef {
+        mnemonic: "jnz",
+        decode: |args| decode_operands(args, &[false, false]),
+        exec: |hh, ops| match hh.value(ops[0]) {
+            0 => Flow::Next,
+            _ => Flow::Jump(hh.value(ops[1])),
+        },
+    },
+    InstrDef {
+        mnemonic: "in",
+        decode: |args| decode_operands(args, &[true]),
+        exec: |hh, ops| match hh.input.pop_front() {
+            Some(n) => {
+                *hh.reg(ops[0]) = n;
+                Flow::Next
+            }
+            None => Flow::Block,
+        },
+    },
+    InstrDef {
+        mnemonic: "out",
+        decode: |args| decode_operands(args, &[false]),
+        exec: |hh, ops| {
+            let n = hh.value(ops[0]);
+            hh.output.push(n);
+            Flow::Next
+        },
+    },
+    InstrDef {
+        mnemonic: "hlt",
+        decode: |args| decode_operands(args, &[]),
+        exec: |_, _| Flow::Halt,
+    },
+];
+
+/// Instructions understood by the assembler on top of `acc`, `jmp` and
+/// `nop`, which are always available.
+#[derive(Default)]
+struct InstrSet {
+    ext: Vec<&'static InstrDef>,
+}
+
+impl InstrSet {
+    /// Instruction set with all `SIM_INSTRS` registered.
+    fn simulation() -> InstrSet {
+        let mut set = InstrSet::default();
+        for def in &SIM_INSTRS {
+            set.register(def).expect("unique mnemonics");
+        }
+        set
+    }
+
+    /// Register an additional instruction, its mnemonic must not be
+    /// taken yet.
+    fn register(&mut self, def: &'static InstrDef) -> Result<(), &'static str> {
+        if ["acc", "jmp", "nop"].contains(&def.mnemonic)
+            || self.ext.iter().any(|d| d.mnemonic == def.mnemonic)
+        {
+            return Err("Mnemonic already registered");
+        }
+        self.ext.push(def);
+        Ok(())
+    }
+
+    /// Decode a single line of assembly.
+    fn decode(&self, line: &str) -> Result<Instr, &'static str> {
+        let mut tokens = line.split_whitespace();
+        let op = tokens.next().ok_or("No OPCODE in input")?;
+
+        match self.ext.iter().find(|def| def.mnemonic == op) {
+            Some(def) => {
+                let args: Vec<_> = tokens.collect();
+                Ok(Instr::Ext(def, (def.decode)(&args)?))
+            }
+            None => line.parse(),
+        }
+    }
+
+    /// Assemble code with one instruction per line.
+    fn assemble(&self, src: &str) -> Result<Vec<Instr>, AsmError> {
+        src.lines()
+            .enumerate()
+            .map(|(idx, line)| {
+                self.decode(line)
+                    .map_err(|msg| AsmError { line: idx + 1, msg })
+            })
+            .collect()
+    }
+}
+
+impl Instr {
+    /// Swap `jmp` and `nop`, other instructions can not be patched.
+    fn flipped(&self) -> Option<Instr> {
+        match *self {
+            Instr::Jmp(n) => Some(Instr::Nop(n)),
+            Instr::Nop(n) => Some(Instr::Jmp(n)),
+            Instr::Acc(_) | Instr::Ext(..) => None,
+        }
+    }
+
+    /// PC of the instruction executed after this instruction at `pc`,
+    /// `None` if the jump target is below 0 or depends on the state of
+    /// the hand held.
+    fn next_pc(&self, pc: usize) -> Option<usize> {
+        match *self {
+            Instr::Jmp(n) => pc.checked_add_signed(n),
+            Instr::Acc(_) | Instr::Nop(_) => pc.checked_add(1),
+            Instr::Ext(..) => None,
+        }
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instr::Acc(n) => write!(f, "acc {:+}", n),
+            Instr::Jmp(n) => write!(f, "jmp {:+}", n),
+            Instr::Nop(n) => write!(f, "nop {:+}", n),
+            Instr::Ext(def, ops) => {
+                write!(f, "{}", def.mnemonic)?;
+                for op in ops.iter().flatten() {
+                    write!(f, " {}", op)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Syntax error on 1-based `line` of the boot code or of a trace.
+#[derive(Debug, PartialEq)]
+struct AsmError {
+    line: usize,
+    msg: &'static str,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+impl From<AsmError> for io::Error {
+    fn from(e: AsmError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+impl FromStr for Instr {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = s.split_whitespace();
+
+        let op = tokens.next().ok_or("No OPCODE in input")?;
+        let arg = tokens
+            .next()
+            .ok_or("No ARGUMENT in input")?
+            .parse::<isize>()
+            .map_err(|_| "ARGUMENT is not a number")?;
+        if tokens.next().is_some() {
+            return Err("Trailing input after ARGUMENT");
+        }
+
+        match op {
+            "acc" => Ok(Instr::Acc(arg)),
+            "jmp" => Ok(Instr::Jmp(arg)),
+            "nop" => Ok(Instr::Nop(arg)),
+            _ => Err("Invalid instruction encountered"),
+        }
+    }
+}
+
+/// Assemble boot code with one instruction per line.
+fn assemble(src: &str) -> Result<Vec<Instr>, AsmError> {
+    InstrSet::default().assemble(src)
+}
+
+/// Disassemble boot code into the same format accepted by `assemble`.
+fn disassemble(code: &[Instr]) -> String {
+    code.iter().map(|instr| format!("{}\n", instr)).collect()
+}
+
+/// State used for loop detection of extension code: pc, acc, registers
+/// and number of pending inputs.
+type State = (usize, isize, [isize; REGS], usize);
+
+/// Brent's cycle detection over the states of a run, needs constant
+/// memory unlike remembering all states.
+///
+/// A state is saved after each power of 2 of steps, a cycle is found once
+/// the current state equals the saved one. This happens within twice the
+/// number of steps until the cycle closes first.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct CycleFinder {
+    saved: Option<State>,
+    power: usize,
+    steps: usize,
+}
+
+impl CycleFinder {
+    /// Feed the next state of the run, returns `true` if it is in a cycle.
+    fn check(&mut self, state: State) -> bool {
+        if self.saved == Some(state) {
+            return true;
+        }
+        if self.steps == self.power {
+            self.saved = Some(state);
+            self.power = (self.power * 2).max(1);
+            self.steps = 0;
+        }
+        self.steps += 1;
+        false
+    }
+}
+
+/// Instructions executed by a run, cheap to fork.
+///
+/// The executed prefix is shared between forks behind an `Rc`, each fork
+/// keeps its own additions separately.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Visited {
+    shared: Rc<HashSet<usize>>,
+    own: HashSet<usize>,
+}
+
+impl Visited {
+    /// Add `pc`, returns `false` if it was executed before.
+    fn insert(&mut self, pc: usize) -> bool {
+        !self.shared.contains(&pc) && self.own.insert(pc)
+    }
+
+    /// Share the visited instructions with a new fork.
+    ///
+    /// Own additions are moved into the shared prefix first. This happens
+    /// in place unless another fork still holds the prefix, so each
+    /// instruction is moved once while forks are dropped in between.
+    fn share(&mut self) -> Visited {
+        if !self.own.is_empty() {
+            Rc::make_mut(&mut self.shared).extend(self.own.drain());
+        }
+        Visited {
+            shared: Rc::clone(&self.shared),
+            own: HashSet::new(),
+        }
+    }
+}
 
 #[derive(Default)]
 struct HandHeld {
     pc: usize,
     acc: isize,
+    /// Optional trace recorder, logs each executed instruction.
+    trace: Option<Trace>,
+    /// Maximum number of instructions executed by `run`, unlimited if
+    /// `None`.
+    step_limit: Option<usize>,
+    /// General purpose registers used by extension instructions.
+    regs: [isize; REGS],
+    input: VecDeque<isize>,
+    output: Vec<isize>,
+    /// Instructions executed by `run` without extension instructions.
+    visited: Visited,
+    /// Loop detection of `run` with extension instructions.
+    cycle: CycleFinder,
+}
+
+/// Saved hand held state that can be restored later, see
+/// `HandHeld::snapshot`.
+#[derive(Debug, Clone, PartialEq)]
+struct Snapshot {
+    pc: usize,
+    acc: isize,
+    regs: [isize; REGS],
+    input: VecDeque<isize>,
+    output_len: usize,
+    trace_len: usize,
+    visited: Visited,
+    cycle: CycleFinder,
+}
+
+/// Executed instruction at `pc` with the accumulator before and after.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TraceEntry {
+    pc: usize,
+    instr: Instr,
+    acc_before: isize,
+    acc_after: isize,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.pc, self.instr, self.acc_before, self.acc_after
+        )
+    }
+}
+
+impl TraceEntry {
+    /// Parse trace entry as written by `Display`, eg `7 acc +3 1 4`, the
+    /// instruction is decoded with `set`.
+    fn parse(s: &str, set: &InstrSet) -> Result<TraceEntry, &'static str> {
+        let tokens: Vec<_> = s.split_whitespace().collect();
+        if tokens.len() < 4 {
+            return Err("Trace entry needs at least 4 fields");
+        }
+        let (instr, accs) = tokens[1..].split_at(tokens.len() - 3);
+
+        Ok(TraceEntry {
+            pc: tokens[0].parse().map_err(|_| "PC is not a number")?,
+            instr: set.decode(&instr.join(" "))?,
+            acc_before: accs[0].parse().map_err(|_| "ACC is not a number")?,
+            acc_after: accs[1].parse().map_err(|_| "ACC is not a number")?,
+        })
+    }
+}
+
+/// Ordered log of executed instructions of a hand held run.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Trace(Vec<TraceEntry>);
+
+impl Trace {
+    /// Write trace with one entry per line.
+    fn write(&self, w: &mut impl Write) -> io::Result<()> {
+        for entry in &self.0 {
+            writeln!(w, "{}", entry)?;
+        }
+        Ok(())
+    }
+
+    /// Read trace as written by `write`, instructions are decoded with
+    /// `set`.
+    fn read(r: impl BufRead, set: &InstrSet) -> io::Result<Trace> {
+        r.lines()
+            .enumerate()
+            .map(|(idx, line)| {
+                TraceEntry::parse(&line?, set)
+                    .map_err(|msg| io::Error::from(AsmError { line: idx + 1, msg }))
+            })
+            .collect::<io::Result<_>>()
+            .map(Trace)
+    }
+
+    /// Index of the first entry where the traces differ, `None` if they
+    /// are equal.
+    ///
+    /// If one trace is a prefix of the other, the length of the shorter
+    /// trace is returned.
+    fn first_divergence(&self, other: &Trace) -> Option<usize> {
+        let common = self.0.iter().zip(&other.0).position(|(e1, e2)| e1 != e2);
+        match common {
+            Some(idx) => Some(idx),
+            None if self.0.len() != other.0.len() => Some(self.0.len().min(other.0.len())),
+            None => None,
+        }
+    }
 }
 
-#[derive(PartialEq)]
+/// Re-run `code` and compare it against a recorded `trace`.
+///
+/// Returns the index of the first trace entry that is not reproduced,
+/// `None` if the run matches the trace.
+fn replay(code: &[Instr], trace: &Trace) -> Option<usize> {
+    let mut h = HandHeld {
+        trace: Some(Trace::default()),
+        ..HandHeld::default()
+    };
+    h.run(code);
+    h.trace.expect("trace enabled").first_divergence(trace)
+}
+
+/// Why the hand held stopped running the boot code.
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum ExitReason {
-    Inf,
+    /// Boot code terminated by reaching the instruction after the last.
     End,
+    /// Instruction at `pc` was about to be executed a second time.
+    Loop { pc: usize },
+    /// Jump at `pc` to `target` below 0 or past the end of the boot code.
+    OutOfBounds { pc: usize, target: isize },
+    /// Step limit reached after `steps` executed instructions.
+    StepLimit { steps: usize },
+    /// Halt instruction at `pc` executed.
+    Halt { pc: usize },
+    /// Instruction at `pc` waits for input, running continues once
+    /// input is available.
+    NoInput { pc: usize },
+    /// Instruction at `pc` overflows, it had no effect.
+    Overflow { pc: usize },
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitReason::End => write!(f, "Terminated"),
+            ExitReason::Loop { pc } => write!(f, "Loop detected at pc {}", pc),
+            ExitReason::OutOfBounds { pc, target } => {
+                write!(f, "Jump at pc {} to {} is out of bounds", pc, target)
+            }
+            ExitReason::StepLimit { steps } => write!(f, "Step limit of {} exceeded", steps),
+            ExitReason::Halt { pc } => write!(f, "Halted at pc {}", pc),
+            ExitReason::NoInput { pc } => write!(f, "Waiting for input at pc {}", pc),
+            ExitReason::Overflow { pc } => write!(f, "Overflow at pc {}", pc),
+        }
+    }
 }
 
 impl HandHeld {
-    fn run(&mut self, code: &Vec<&str>) -> io::Result<ExitReason> {
-        let mut prev_pcs = HashSet::new();
+    /// Register addressed by `op`, which the decoder ensured to be one.
+    fn reg(&mut self, op: Option<Operand>) -> &mut isize {
+        match op {
+            Some(Operand::Reg(r)) => &mut self.regs[r],
+            _ => unreachable!("Operand is not a register"),
+        }
+    }
 
-        loop {
-            prev_pcs.insert(self.pc);
+    /// Value of a register or immediate operand.
+    fn value(&self, op: Option<Operand>) -> isize {
+        match op {
+            Some(Operand::Reg(r)) => self.regs[r],
+            Some(Operand::Imm(n)) => n,
+            None => unreachable!("Missing operand"),
+        }
+    }
 
-            let instr = code
-                .get(self.pc)
-                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Illegal PC"))?;
+    /// Execute the instruction at the current `pc`.
+    ///
+    /// Returns the exit reason if the boot code can not continue, either
+    /// because it terminated or because the instruction would jump out of
+    /// bounds, halts, waits for input or overflows. In the latter cases
+    /// `pc` is left unchanged and only the halt is traced.
+    fn step(&mut self, code: &[Instr]) -> Option<ExitReason> {
+        let pc = self.pc;
+        let instr = match code.get(pc) {
+            Some(instr) => *instr,
+            None if pc == code.len() => return Some(ExitReason::End),
+            None => {
+                return Some(ExitReason::OutOfBounds {
+                    pc,
+                    target: pc as isize,
+                })
+            }
+        };
 
-            match HandHeld::decode(instr)? {
-                ("acc", n) => {
-                    self.acc += n;
-                    self.pc += 1;
-                }
-                ("jmp", n) => {
-                    if n.is_positive() {
-                        self.pc += n as usize;
-                    } else {
-                        self.pc -= n.abs() as usize;
-                    }
+        let acc_before = self.acc;
+        let flow = match instr {
+            Instr::Acc(n) => match self.acc.checked_add(n) {
+                Some(acc) => {
+                    self.acc = acc;
+                    Flow::Next
                 }
-                ("nop", _) => {
-                    self.pc += 1;
-                }
-                _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "Invalid instruction encountered",
-                    ));
+                None => Flow::Overflow,
+            },
+            Instr::Jmp(n) => Flow::Jump(n),
+            Instr::Nop(_) => Flow::Next,
+            Instr::Ext(def, ops) => (def.exec)(self, &ops),
+        };
+
+        let halt = match flow {
+            Flow::Next => {
+                self.pc = pc + 1;
+                false
+            }
+            Flow::Jump(n) => {
+                let target = (pc as isize).saturating_add(n);
+                if target < 0 || target as usize > code.len() {
+                    return Some(ExitReason::OutOfBounds { pc, target });
                 }
+                self.pc = target as usize;
+                false
             }
+            // Halt instruction is executed, so it is still traced.
+            Flow::Halt => true,
+            Flow::Block => return Some(ExitReason::NoInput { pc }),
+            Flow::Overflow => return Some(ExitReason::Overflow { pc }),
+        };
+
+        if let Some(trace) = &mut self.trace {
+            trace.0.push(TraceEntry {
+                pc,
+                instr,
+                acc_before,
+                acc_after: self.acc,
+            });
+        }
+
+        if halt {
+            return Some(ExitReason::Halt { pc });
+        }
+        (self.pc == code.len()).then_some(ExitReason::End)
+    }
 
-            if self.pc == code.len() {
-                return Ok(ExitReason::End);
-            } else if prev_pcs.contains(&self.pc) {
-                return Ok(ExitReason::Inf);
+    /// Run the boot code until it terminates, loops, jumps out of bounds
+    /// or reaches the step limit.
+    fn run(&mut self, code: &[Instr]) -> ExitReason {
+        self.run_with(code, loops_by_pc(code))
+    }
+
+    /// `run` with `by_pc` already known, see `loops_by_pc`.
+    fn run_with(&mut self, code: &[Instr], by_pc: bool) -> ExitReason {
+        let mut steps = 0;
+
+        loop {
+            if self.step_limit.is_some_and(|limit| steps >= limit) {
+                return ExitReason::StepLimit { steps };
+            } else if let Some(reason) = self.advance(code, by_pc) {
+                return reason;
             }
+            steps += 1;
         }
     }
 
-    fn decode(instr: &str) -> io::Result<(&str, isize)> {
-        let mut instr = instr.splitn(2, ' ');
+    /// Execute the next instruction unless the run loops, see
+    /// `loops_by_pc` for `by_pc`.
+    fn advance(&mut self, code: &[Instr], by_pc: bool) -> Option<ExitReason> {
+        let looped = if by_pc {
+            !self.visited.insert(self.pc)
+        } else {
+            let state = (self.pc, self.acc, self.regs, self.input.len());
+            self.cycle.check(state)
+        };
 
-        let op = instr.next().ok_or(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "No OPCODE in input",
-        ))?;
+        if looped {
+            return Some(ExitReason::Loop { pc: self.pc });
+        }
+        self.step(code)
+    }
 
-        let arg = instr
-            .next()
-            .ok_or(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "No ARGUMENT in input",
-            ))?
-            .parse::<isize>()
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "ARGUMENT is not a number"))?;
+    /// Save the current state, output and trace are saved by length as
+    /// they only grow and visited instructions are shared.
+    fn snapshot(&mut self) -> Snapshot {
+        Snapshot {
+            pc: self.pc,
+            acc: self.acc,
+            regs: self.regs,
+            input: self.input.clone(),
+            output_len: self.output.len(),
+            trace_len: self.trace.as_ref().map_or(0, |trace| trace.0.len()),
+            visited: self.visited.share(),
+            cycle: self.cycle.clone(),
+        }
+    }
+
+    /// Rewind to a snapshot taken earlier from this hand held.
+    fn restore(&mut self, snap: &Snapshot) {
+        self.pc = snap.pc;
+        self.acc = snap.acc;
+        self.regs = snap.regs;
+        self.input.clone_from(&snap.input);
+        self.output.truncate(snap.output_len);
+        if let Some(trace) = &mut self.trace {
+            trace.0.truncate(snap.trace_len);
+        }
+        self.visited.clone_from(&snap.visited);
+        self.cycle.clone_from(&snap.cycle);
+    }
+
+    /// Independent hand held to explore a branch without affecting this
+    /// one. Visited instructions are shared and the fork only traces its
+    /// own steps, if this hand held traces.
+    fn fork(&mut self) -> HandHeld {
+        HandHeld {
+            pc: self.pc,
+            acc: self.acc,
+            trace: self.trace.as_ref().map(|_| Trace::default()),
+            step_limit: self.step_limit,
+            regs: self.regs,
+            input: self.input.clone(),
+            output: self.output.clone(),
+            visited: self.visited.share(),
+            cycle: self.cycle.clone(),
+        }
+    }
+}
+
+/// Without extension instructions the control flow does not depend on
+/// the state, so executing an instruction twice is a loop. Otherwise
+/// only a repeated state is, which is found with a `CycleFinder`.
+fn loops_by_pc(code: &[Instr]) -> bool {
+    !code.iter().any(|instr| matches!(instr, Instr::Ext(..)))
+}
+
+/// Why the debugger stopped executing.
+#[derive(Debug, PartialEq)]
+enum Stop {
+    /// Single step done.
+    Step,
+    /// Next instruction has a breakpoint.
+    Breakpoint,
+    /// Next instruction was already executed before.
+    Loop,
+    /// Boot code terminated.
+    End,
+    /// Next instruction can not be executed.
+    Exit(ExitReason),
+}
+
+/// Step debugger for the hand held.
+struct Debugger<'a> {
+    hh: HandHeld,
+    code: &'a [Instr],
+    breakpoints: BTreeSet<usize>,
+    /// Number of times each instruction was executed.
+    visited: Vec<usize>,
+    /// Marked state to rewind to.
+    mark: Option<(Snapshot, Vec<usize>)>,
+}
+
+impl<'a> Debugger<'a> {
+    fn new(code: &'a [Instr]) -> Debugger<'a> {
+        Debugger {
+            hh: HandHeld::default(),
+            code,
+            breakpoints: BTreeSet::new(),
+            visited: vec![0; code.len()],
+            mark: None,
+        }
+    }
+
+    /// Restart the boot code, breakpoints and the mark are kept.
+    fn reset(&mut self) {
+        self.hh = HandHeld::default();
+        self.visited = vec![0; self.code.len()];
+    }
+
+    /// Rewind to the marked state, returns `false` if nothing is marked.
+    fn rewind(&mut self) -> bool {
+        match &self.mark {
+            Some((snap, visited)) => {
+                self.hh.restore(snap);
+                self.visited.clone_from(visited);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Execute a single instruction.
+    fn step(&mut self) -> Stop {
+        let pc = self.hh.pc;
+        if pc == self.code.len() {
+            return Stop::End;
+        }
+
+        let exit = self.hh.step(self.code);
+        if let Some(reason) = exit.filter(|reason| *reason != ExitReason::End) {
+            return Stop::Exit(reason);
+        }
+
+        self.visited[pc] += 1;
+        match exit {
+            Some(_) => Stop::End,
+            None if self.visited[self.hh.pc] > 0 => Stop::Loop,
+            None => Stop::Step,
+        }
+    }
+
+    /// Execute up to `n` instructions, stopping early at a loop or the
+    /// end of the boot code.
+    fn step_n(&mut self, n: usize) -> Stop {
+        let mut stop = Stop::Step;
+        for _ in 0..n {
+            stop = self.step();
+            if stop != Stop::Step {
+                break;
+            }
+        }
+        stop
+    }
+
+    /// Execute until a breakpoint is hit, a loop is detected or the
+    /// boot code terminates.
+    fn cont(&mut self) -> Stop {
+        loop {
+            match self.step() {
+                Stop::Step if self.breakpoints.contains(&self.hh.pc) => return Stop::Breakpoint,
+                Stop::Step => {}
+                stop => return stop,
+            }
+        }
+    }
+
+    /// Print the instruction at `pc`, marking the current `pc` with `>`
+    /// and breakpoints with `*`.
+    fn print_instr(&self, pc: usize, out: &mut impl Write) -> io::Result<()> {
+        let cur = if pc == self.hh.pc { '>' } else { ' ' };
+        let bp = if self.breakpoints.contains(&pc) {
+            '*'
+        } else {
+            ' '
+        };
+        match self.code.get(pc) {
+            Some(instr) => writeln!(
+                out,
+                "{}{}{:5}: {:10} (x{})",
+                cur,
+                bp,
+                pc,
+                instr.to_string(),
+                self.visited[pc]
+            ),
+            None => writeln!(out, "{}{}{:5}: <end>", cur, bp, pc),
+        }
+    }
+}
+
+const DEBUGGER_HELP: &str = "\
+Commands:
+  b <pc> ..... set breakpoint
+  d <pc> ..... delete breakpoint
+  s [n] ...... step n instructions (default 1)
+  c .......... continue until breakpoint, loop or end
+  p .......... print pc, acc and current instruction
+  v .......... print visited instructions
+  r .......... restart boot code
+  m .......... mark current state
+  u .......... rewind to marked state
+  q .......... quit
+";
+
+/// Run the debugger REPL reading commands from `input` until `q` or
+/// end of input.
+fn repl(dbg: &mut Debugger, input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+    write!(out, "(dbg) ")?;
+    out.flush()?;
+
+    for line in input.lines() {
+        let line = line?;
+        let tokens: Vec<_> = line.split_whitespace().collect();
+        let num = tokens.get(1).and_then(|n| n.parse::<usize>().ok());
+
+        let stop = match (tokens.as_slice(), num) {
+            ([], _) => None,
+            (["b", _], Some(pc)) => {
+                dbg.breakpoints.insert(pc);
+                None
+            }
+            (["d", _], Some(pc)) => {
+                dbg.breakpoints.remove(&pc);
+                None
+            }
+            (["s"], _) => Some(dbg.step()),
+            (["s", _], Some(n)) => Some(dbg.step_n(n)),
+            (["c"], _) => Some(dbg.cont()),
+            (["p"], _) => {
+                writeln!(out, "pc={} acc={}", dbg.hh.pc, dbg.hh.acc)?;
+                dbg.print_instr(dbg.hh.pc, out)?;
+                None
+            }
+            (["v"], _) => {
+                for pc in (0..dbg.code.len()).filter(|&pc| dbg.visited[pc] > 0) {
+                    dbg.print_instr(pc, out)?;
+                }
+                None
+            }
+            (["r"], _) => {
+                dbg.reset();
+                None
+            }
+            (["m"], _) => {
+                dbg.mark = Some((dbg.hh.snapshot(), dbg.visited.clone()));
+                None
+            }
+            (["u"], _) => {
+                if !dbg.rewind() {
+                    writeln!(out, "No state marked")?;
+                }
+                None
+            }
+            (["q"], _) => return Ok(()),
+            _ => {
+                write!(out, "{}", DEBUGGER_HELP)?;
+                None
+            }
+        };
+
+        if let Some(stop) = stop {
+            writeln!(out, "{:?} pc={} acc={}", stop, dbg.hh.pc, dbg.hh.acc)?;
+            dbg.print_instr(dbg.hh.pc, out)?;
+        }
 
-        Ok((op, arg))
+        write!(out, "(dbg) ")?;
+        out.flush()?;
     }
+
+    Ok(())
+}
+
+fn parse_boot_code() -> io::Result<Vec<Instr>> {
+    Ok(assemble(&aoc20::read_input_to_string("day08"))?)
 }
 
 fn challenge1() -> io::Result<isize> {
-    let input = aoc20::read_input_to_string("day08");
-    let boot_code: Vec<&str> = input.lines().collect();
+    let boot_code = parse_boot_code()?;
 
     let mut h = HandHeld::default();
-    h.run(&boot_code)?;
-    Ok(h.acc)
+    match h.run(&boot_code) {
+        ExitReason::Loop { .. } => Ok(h.acc),
+        reason => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            reason.to_string(),
+        )),
+    }
 }
 
-fn challenge2() -> io::Result<isize> {
-    let input = aoc20::read_input_to_string("day08");
-    let mut boot_code: Vec<&str> = input.lines().collect();
-
-    struct Patch<'a> {
-        old: &'a str,
-        new: String,
-        idx: usize,
-    }
-
-    // Collect all patch candidates.
-    let patches: Vec<_> = boot_code
-        .iter()
-        .enumerate()
-        .filter_map(|(idx, instr)| {
-            let new = match &instr[0..3] {
-                "jmp" => instr.replace("jmp", "nop"),
-                "nop" => instr.replace("nop", "jmp"),
-                _ => return None,
-            };
-            Some(Patch {
-                old: instr,
-                new,
-                idx,
-            })
-        })
-        .collect();
+/// Single instruction patch that makes the boot code terminate.
+#[derive(Debug, PartialEq)]
+struct Repair {
+    idx: usize,
+    old: Instr,
+    new: Instr,
+    /// Accumulator after the patched boot code terminated.
+    acc: isize,
+}
 
-    for patch in &patches {
-        // Replace original instruction with patched instruction.
-        boot_code[patch.idx] = patch.new.as_str();
+/// Find the single `jmp`/`nop` flip that makes the boot code terminate.
+///
+/// Runs in linear time in the length of the boot code by analysing the
+/// control flow graph instead of trying every flip:
+///  1. Mark all instructions from which the unpatched boot code
+///     terminates, by walking the reversed control flow graph starting
+///     at the end of the boot code.
+///  2. Walk the instructions executed by the unpatched boot code until
+///     it loops. The first flip on this path that jumps into a marked
+///     instruction (or directly to the end) is the patch. The path
+///     following the flip can not pass the flipped instruction again,
+///     otherwise the unpatched instruction would be marked as well.
+fn repair(code: &[Instr]) -> io::Result<Repair> {
+    if code.iter().any(|instr| matches!(instr, Instr::Ext(..))) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Only boot code with acc, jmp and nop can be repaired",
+        ));
+    }
+    let end = code.len();
 
-        // Evaluate patched boot code.
-        let mut h = HandHeld::default();
-        if h.run(&boot_code)? == ExitReason::End {
-            return Ok(h.acc);
+    // Reversed control flow graph, index `end` is the virtual end node.
+    let mut preds = vec![Vec::new(); end + 1];
+    for (pc, instr) in code.iter().enumerate() {
+        if let Some(next) = instr.next_pc(pc).filter(|&next| next <= end) {
+            preds[next].push(pc);
+        }
+    }
+
+    let mut terminates = vec![false; end + 1];
+    terminates[end] = true;
+    let mut queue = vec![end];
+    while let Some(pc) = queue.pop() {
+        for &pred in &preds[pc] {
+            if !terminates[pred] {
+                terminates[pred] = true;
+                queue.push(pred);
+            }
         }
+    }
+
+    let mut visited = vec![false; end];
+    let mut pc = 0;
+    while let Some(instr) = code.get(pc).filter(|_| !visited[pc]) {
+        visited[pc] = true;
 
-        // Replace patched instruction with original instruction.
-        boot_code[patch.idx] = patch.old;
+        if let Some(new) = instr.flipped() {
+            if new
+                .next_pc(pc)
+                .is_some_and(|next| next <= end && terminates[next])
+            {
+                let mut patched = code.to_vec();
+                patched[pc] = new;
+
+                let mut h = HandHeld::default();
+                h.run(&patched);
+                return Ok(Repair {
+                    idx: pc,
+                    old: *instr,
+                    new,
+                    acc: h.acc,
+                });
+            }
+        }
+
+        pc = match instr.next_pc(pc) {
+            Some(next) => next,
+            None => break,
+        };
     }
 
     Err(io::Error::new(
-        io::ErrorKind::Other,
+        io::ErrorKind::NotFound,
         "No proper patch found",
     ))
 }
 
+/// Outcome of running the boot code with the instruction at `idx` flipped.
+#[derive(Debug, PartialEq)]
+struct PatchOutcome {
+    idx: usize,
+    reason: ExitReason,
+    acc: isize,
+}
+
+/// Try flipping every `jmp`/`nop` executed by the unpatched boot code.
+///
+/// Instead of restarting from pc 0 for each patch, the unpatched run is
+/// forked in front of each candidate and only the fork explores the
+/// patched branch. Forks share the visited instructions of the unpatched
+/// run, so each patch costs only the length of its branch. Outcomes are
+/// returned in execution order.
+///
+/// Only the first execution of a candidate is forked, the patched boot
+/// code already diverges there.
+fn explore_patches(code: &[Instr]) -> Vec<PatchOutcome> {
+    let mut code = code.to_vec();
+    let by_pc = loops_by_pc(&code);
+    let mut h = HandHeld::default();
+    let mut outcomes = Vec::new();
+    let mut patched = HashSet::new();
+
+    loop {
+        let idx = h.pc;
+        let new = code.get(idx).and_then(Instr::flipped);
+        if let Some(new) = new.filter(|_| patched.insert(idx)) {
+            let mut branch = h.fork();
+            let old = std::mem::replace(&mut code[idx], new);
+            // Flipping a `jmp`/`nop` keeps `by_pc`.
+            let reason = branch.run_with(&code, by_pc);
+            outcomes.push(PatchOutcome {
+                idx,
+                reason,
+                acc: branch.acc,
+            });
+            code[idx] = old;
+        }
+
+        if h.advance(&code, by_pc).is_some() {
+            return outcomes;
+        }
+    }
+}
+
+fn challenge2() -> io::Result<isize> {
+    Ok(repair(&parse_boot_code()?)?.acc)
+}
+
+/// Maximum number of instructions executed by a simulation program.
+const SIM_STEP_LIMIT: usize = 10_000_000;
+
+fn usage() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Usage: day08 [disasm | debug | repair | patches | trace <file> [<patch_idx>] | replay <file> | \
+         diff <file> <file> | sim <file> [<input>..]]",
+    )
+}
+
 fn main() -> io::Result<()> {
-    println!("{}", challenge1()?);
-    println!("{}", challenge2()?);
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => {
+            println!("{}", challenge1()?);
+            println!("{}", challenge2()?);
+        }
+        [cmd] if cmd == "disasm" => print!("{}", disassemble(&parse_boot_code()?)),
+        [cmd] if cmd == "debug" => {
+            let boot_code = parse_boot_code()?;
+            repl(
+                &mut Debugger::new(&boot_code),
+                io::stdin().lock(),
+                &mut io::stdout(),
+            )?;
+        }
+        [cmd, file, patch @ ..] if cmd == "trace" && patch.len() <= 1 => {
+            let mut boot_code = parse_boot_code()?;
+            if let Some(idx) = patch.first() {
+                let idx: usize = idx.parse().map_err(|_| usage())?;
+                boot_code[idx] = boot_code.get(idx).and_then(Instr::flipped).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Can not patch instruction")
+                })?;
+            }
+
+            let mut h = HandHeld {
+                trace: Some(Trace::default()),
+                ..HandHeld::default()
+            };
+            println!("{}", h.run(&boot_code));
+
+            let mut w = BufWriter::new(File::create(file)?);
+            h.trace.expect("trace enabled").write(&mut w)?;
+            w.flush()?;
+        }
+        [cmd, file, input @ ..] if cmd == "sim" => {
+            let code = InstrSet::simulation().assemble(&std::fs::read_to_string(file)?)?;
+            let mut h = HandHeld {
+                input: input
+                    .iter()
+                    .map(|n| n.parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| usage())?,
+                step_limit: Some(SIM_STEP_LIMIT),
+                ..HandHeld::default()
+            };
+            let reason = h.run(&code);
+            for n in &h.output {
+                println!("{}", n);
+            }
+            println!("{}", reason);
+        }
+        [cmd] if cmd == "patches" => {
+            let boot_code = parse_boot_code()?;
+            for o in explore_patches(&boot_code) {
+                let old = boot_code[o.idx];
+                let new = old.flipped().expect("patched instruction");
+                println!("{}: {} -> {}: {}, acc={}", o.idx, old, new, o.reason, o.acc);
+            }
+        }
+        [cmd] if cmd == "repair" => {
+            let r = repair(&parse_boot_code()?)?;
+            println!("{}: {} -> {}, acc={}", r.idx, r.old, r.new, r.acc);
+        }
+        [cmd, file] if cmd == "replay" => {
+            let trace = Trace::read(BufReader::new(File::open(file)?), &InstrSet::default())?;
+            match replay(&parse_boot_code()?, &trace) {
+                Some(idx) => println!("Run diverges from trace at entry {}", idx),
+                None => println!("Run matches trace"),
+            }
+        }
+        [cmd, file1, file2] if cmd == "diff" => {
+            // Traces of simulation programs can be compared as well.
+            let set = InstrSet::simulation();
+            let t1 = Trace::read(BufReader::new(File::open(file1)?), &set)?;
+            let t2 = Trace::read(BufReader::new(File::open(file2)?), &set)?;
+            match t1.first_divergence(&t2) {
+                Some(idx) => {
+                    println!("Traces diverge at entry {}", idx);
+                    let show = |t: &Trace| {
+                        t.0.get(idx)
+                            .map_or(String::from("<end>"), |e| e.to_string())
+                    };
+                    println!("< {}", show(&t1));
+                    println!("> {}", show(&t2));
+                }
+                None => println!("Traces are equal"),
+            }
+        }
+        _ => return Err(usage()),
+    }
     Ok(())
 }
 
@@ -154,4 +1199,383 @@ mod tests {
         assert_eq!(challenge2()?, 969);
         Ok(())
     }
+
+    #[test]
+    fn check_debugger() -> io::Result<()> {
+        let code =
+            assemble("nop +0\nacc +1\njmp +4\nacc +3\njmp -3\nacc -99\nacc +1\njmp -4\nacc +6")?;
+        let mut dbg = Debugger::new(&code);
+
+        dbg.breakpoints.insert(6);
+        assert_eq!(dbg.cont(), Stop::Breakpoint);
+        assert_eq!((dbg.hh.pc, dbg.hh.acc), (6, 1));
+        assert_eq!(dbg.step(), Stop::Step);
+        assert_eq!(dbg.cont(), Stop::Loop);
+        assert_eq!((dbg.hh.pc, dbg.hh.acc), (1, 5));
+        assert_eq!(dbg.visited, vec![1, 1, 1, 1, 1, 0, 1, 1, 0]);
+
+        let input = "s 2\nd 6\nb 4\nc\nv\nx\nq\np\n".as_bytes();
+        let mut out = Vec::new();
+        dbg.reset();
+        repl(&mut dbg, input, &mut out)?;
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out,
+            format!(
+                "(dbg) Step pc=2 acc=1\n\
+                 >     2: jmp +4     (x0)\n\
+                 (dbg) (dbg) (dbg) Breakpoint pc=4 acc=5\n\
+                 >*    4: jmp -3     (x0)\n\
+                 (dbg)       0: nop +0     (x1)\n\
+                 \x20     1: acc +1     (x1)\n\
+                 \x20     2: jmp +4     (x1)\n\
+                 \x20     3: acc +3     (x1)\n\
+                 \x20     6: acc +1     (x1)\n\
+                 \x20     7: jmp -4     (x1)\n\
+                 (dbg) {}(dbg) ",
+                DEBUGGER_HELP
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_exit_reasons() -> io::Result<()> {
+        let run = |code: &[Instr], step_limit| {
+            let mut h = HandHeld {
+                step_limit,
+                ..HandHeld::default()
+            };
+            let reason = h.run(code);
+            (reason, h.pc, h.acc)
+        };
+
+        let code = assemble("acc +1\nacc +2")?;
+        assert_eq!(run(&code, None), (ExitReason::End, 2, 3));
+        let code = assemble("acc +1\njmp -1")?;
+        assert_eq!(run(&code, None), (ExitReason::Loop { pc: 0 }, 0, 1));
+        let code = assemble("acc +1\njmp -2")?;
+        assert_eq!(
+            run(&code, None),
+            (ExitReason::OutOfBounds { pc: 1, target: -1 }, 1, 1)
+        );
+        let code = assemble("nop +0\njmp +2")?;
+        assert_eq!(
+            run(&code, None),
+            (ExitReason::OutOfBounds { pc: 1, target: 3 }, 1, 0)
+        );
+        let code = assemble("acc +1\nacc +1\nacc +1")?;
+        assert_eq!(
+            run(&code, Some(2)),
+            (ExitReason::StepLimit { steps: 2 }, 2, 2)
+        );
+        assert_eq!(run(&code, Some(3)), (ExitReason::End, 3, 3));
+
+        let code = assemble("acc +1\njmp -5")?;
+        let mut dbg = Debugger::new(&code);
+        assert_eq!(
+            dbg.cont(),
+            Stop::Exit(ExitReason::OutOfBounds { pc: 1, target: -4 })
+        );
+        assert_eq!(dbg.visited, vec![1, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn check_instr_set() -> io::Result<()> {
+        let set = InstrSet::simulation();
+        let src = "in a\nset b +0\njz a +4\nadd b a\nadd a -1\njmp -3\nout b\nhlt\n";
+        let code = set.assemble(src)?;
+        assert_eq!(disassemble(&code), src);
+
+        let mut h = HandHeld::default();
+        assert_eq!(h.run(&code), ExitReason::NoInput { pc: 0 });
+        h.input.push_back(4);
+        assert_eq!(h.run(&code), ExitReason::Halt { pc: 7 });
+        assert_eq!(h.output, vec![10]);
+
+        // Revisiting an instruction is only a loop if the state repeats.
+        let code = set.assemble("set a +1\nadd b +1\njnz a -1")?;
+        let mut h = HandHeld {
+            step_limit: Some(100),
+            ..HandHeld::default()
+        };
+        assert_eq!(h.run(&code), ExitReason::StepLimit { steps: 100 });
+        let code = set.assemble("set a +1\njnz a +0")?;
+        assert_eq!(HandHeld::default().run(&code), ExitReason::Loop { pc: 1 });
+        // Cycle of several steps, reached after a few steps.
+        let code = set.assemble("set a +3\nadd a -1\njnz a -1\nadd a +2\njmp -3")?;
+        assert!(matches!(
+            HandHeld::default().run(&code),
+            ExitReason::Loop { .. }
+        ));
+
+        // Never repeating runs end at the step limit in constant memory.
+        let code = set.assemble("add a +1\njmp -1")?;
+        let mut h = HandHeld {
+            step_limit: Some(1_000_000),
+            ..HandHeld::default()
+        };
+        assert_eq!(h.run(&code), ExitReason::StepLimit { steps: 1_000_000 });
+        assert_eq!(h.regs[0], 500_000);
+
+        let code = set.assemble(&format!("set a {}\nadd a +1", isize::MAX))?;
+        let mut h = HandHeld::default();
+        assert_eq!(h.run(&code), ExitReason::Overflow { pc: 1 });
+        assert_eq!((h.pc, h.regs[0]), (1, isize::MAX));
+        let code = assemble(&format!("acc {}\nacc -1\nacc +2", isize::MAX))?;
+        assert_eq!(
+            HandHeld::default().run(&code),
+            ExitReason::Overflow { pc: 2 }
+        );
+
+        assert_eq!(
+            set.assemble("nop +0\nset +1 a"),
+            Err(AsmError {
+                line: 2,
+                msg: "OPERAND is not a register"
+            })
+        );
+        assert_eq!(
+            assemble("hlt"),
+            Err(AsmError {
+                line: 1,
+                msg: "No ARGUMENT in input"
+            })
+        );
+
+        let mut set = InstrSet::default();
+        assert!(set.register(&SIM_INSTRS[6]).is_ok());
+        assert!(set.register(&SIM_INSTRS[6]).is_err());
+        assert_eq!(set.decode("hlt"), Ok(Instr::Ext(&SIM_INSTRS[6], [None; 2])));
+        Ok(())
+    }
+
+    #[test]
+    fn check_snapshot() -> io::Result<()> {
+        let code = InstrSet::simulation().assemble("in a\nacc +1\nout a\nadd a +1\njmp -3")?;
+        let mut h = HandHeld {
+            trace: Some(Trace::default()),
+            input: VecDeque::from(vec![5]),
+            step_limit: Some(3),
+            ..HandHeld::default()
+        };
+        h.run(&code);
+        let snap = h.snapshot();
+
+        let mut fork = h.fork();
+        fork.step_limit = Some(6);
+        fork.run(&code);
+        assert_eq!((fork.pc, fork.acc, fork.output.clone()), (1, 2, vec![5, 6]));
+        assert_eq!(fork.trace.map(|t| t.0.len()), Some(6));
+        assert_eq!((h.pc, h.acc, h.output.clone()), (3, 1, vec![5]));
+
+        h.step_limit = Some(4);
+        h.run(&code);
+        assert_eq!((h.pc, h.acc, h.output.clone()), (3, 2, vec![5, 6]));
+        h.restore(&snap);
+        assert_eq!(h.snapshot(), snap);
+        assert_eq!((h.pc, h.acc, h.output.clone()), (3, 1, vec![5]));
+        assert_eq!(h.trace.as_ref().map(|t| t.0.len()), Some(3));
+
+        let mut dbg = Debugger::new(&code);
+        assert!(!dbg.rewind());
+        dbg.hh.input.push_back(1);
+        dbg.step_n(2);
+        dbg.mark = Some((dbg.hh.snapshot(), dbg.visited.clone()));
+        assert_eq!(dbg.step_n(4), Stop::Loop);
+        assert_eq!(
+            (dbg.hh.pc, dbg.hh.acc, dbg.visited.clone()),
+            (1, 1, vec![1, 1, 1, 1, 1])
+        );
+        assert!(dbg.rewind());
+        assert_eq!(
+            (dbg.hh.pc, dbg.hh.acc, dbg.visited.clone()),
+            (2, 1, vec![1, 1, 0, 0, 0])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_fork_visited() -> io::Result<()> {
+        let code =
+            assemble("nop +0\nacc +1\njmp +4\nacc +3\njmp -3\nacc -99\nacc +1\njmp -4\nacc +6")?;
+        let mut h = HandHeld {
+            step_limit: Some(3),
+            ..HandHeld::default()
+        };
+        h.run(&code);
+
+        // Forks share the prefix, additions stay with each fork.
+        let mut fork = h.fork();
+        assert!(Rc::ptr_eq(&h.visited.shared, &fork.visited.shared));
+        assert_eq!(fork.visited.shared.len(), 3);
+        fork.step_limit = None;
+        assert_eq!(fork.run(&code), ExitReason::Loop { pc: 1 });
+        assert_eq!(fork.visited.own.len(), 4);
+        h.step_limit = None;
+        assert_eq!(h.run(&code), ExitReason::Loop { pc: 1 });
+        assert_eq!(h.visited.own.len(), 4);
+
+        // Without other forks the prefix is extended in place.
+        drop(fork);
+        let shared = Rc::as_ptr(&h.visited.shared);
+        let snap = h.snapshot();
+        assert_eq!(Rc::as_ptr(&snap.visited.shared), shared);
+        assert_eq!(snap.visited.shared.len(), 7);
+        Ok(())
+    }
+
+    #[test]
+    fn check_explore_patches() -> io::Result<()> {
+        let code =
+            assemble("nop +0\nacc +1\njmp +4\nacc +3\njmp -3\nacc -99\nacc +1\njmp -4\nacc +6")?;
+        let outcomes: Vec<_> = explore_patches(&code)
+            .into_iter()
+            .map(|o| (o.idx, o.reason, o.acc))
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                (0, ExitReason::Loop { pc: 0 }, 0),
+                (2, ExitReason::Loop { pc: 1 }, 4),
+                (7, ExitReason::End, 8),
+                (4, ExitReason::Loop { pc: 6 }, -94),
+            ]
+        );
+
+        // Unpatched run loops back to a candidate, which is not explored
+        // again.
+        let outcomes: Vec<_> = explore_patches(&assemble("nop +0\njmp -1")?)
+            .into_iter()
+            .map(|o| (o.idx, o.reason))
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![(0, ExitReason::Loop { pc: 0 }), (1, ExitReason::End)]
+        );
+
+        let boot_code = parse_boot_code()?;
+        let repaired = repair(&boot_code)?;
+        let ends: Vec<_> = explore_patches(&boot_code)
+            .into_iter()
+            .filter(|o| o.reason == ExitReason::End)
+            .collect();
+        assert_eq!(
+            ends,
+            vec![PatchOutcome {
+                idx: repaired.idx,
+                reason: ExitReason::End,
+                acc: repaired.acc
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_repair() -> io::Result<()> {
+        let code =
+            assemble("nop +0\nacc +1\njmp +4\nacc +3\njmp -3\nacc -99\nacc +1\njmp -4\nacc +6")?;
+        assert_eq!(
+            repair(&code)?,
+            Repair {
+                idx: 7,
+                old: Instr::Jmp(-4),
+                new: Instr::Nop(-4),
+                acc: 8
+            }
+        );
+
+        // Both flips terminate, the first one on the executed path wins.
+        let code = assemble("nop +3\njmp +0\nacc +1")?;
+        assert_eq!(
+            repair(&code)?,
+            Repair {
+                idx: 0,
+                old: Instr::Nop(3),
+                new: Instr::Jmp(3),
+                acc: 0
+            }
+        );
+
+        assert!(repair(&assemble("acc +1\njmp -1\njmp -2")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_trace() -> io::Result<()> {
+        let mut code = assemble("nop +0\nacc +1\njmp +2\nacc +3\nacc -2\njmp -3")?;
+
+        let mut h = HandHeld {
+            trace: Some(Trace::default()),
+            ..HandHeld::default()
+        };
+        h.run(&code);
+        let trace = h.trace.unwrap();
+
+        let mut out = Vec::new();
+        trace.write(&mut out)?;
+        assert_eq!(
+            String::from_utf8(out.clone()).unwrap(),
+            "0 nop +0 0 0\n1 acc +1 0 1\n2 jmp +2 1 1\n4 acc -2 1 -1\n5 jmp -3 -1 -1\n"
+        );
+        assert_eq!(Trace::read(out.as_slice(), &InstrSet::default())?, trace);
+        assert_eq!(replay(&code, &trace), None);
+
+        code[4] = Instr::Acc(2);
+        assert_eq!(replay(&code, &trace), Some(3));
+        code[4] = Instr::Acc(-2);
+        code[5] = Instr::Nop(-3);
+        assert_eq!(replay(&code, &trace), Some(4));
+        let set = InstrSet::simulation();
+        assert!(Trace::read("0 nop +0 0\n".as_bytes(), &set).is_err());
+        assert!(Trace::read("0 nop +0 0 0 0\n".as_bytes(), &set).is_err());
+
+        // Traces with extension instructions can be read back.
+        let code = set.assemble("set a +1\nout a\nacc +2\nhlt")?;
+        let mut h = HandHeld {
+            trace: Some(Trace::default()),
+            ..HandHeld::default()
+        };
+        assert_eq!(h.run(&code), ExitReason::Halt { pc: 3 });
+        let trace = h.trace.unwrap();
+        let mut out = Vec::new();
+        trace.write(&mut out)?;
+        assert_eq!(
+            String::from_utf8(out.clone()).unwrap(),
+            "0 set a +1 0 0\n1 out a 0 0\n2 acc +2 0 2\n3 hlt 2 2\n"
+        );
+        assert_eq!(Trace::read(out.as_slice(), &set)?, trace);
+        assert!(Trace::read(out.as_slice(), &InstrSet::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_assemble() {
+        let src = "nop +0\nacc +1\njmp -4\nacc -99\n";
+        let code = assemble(src).unwrap();
+        assert_eq!(
+            code,
+            vec![
+                Instr::Nop(0),
+                Instr::Acc(1),
+                Instr::Jmp(-4),
+                Instr::Acc(-99)
+            ]
+        );
+        assert_eq!(disassemble(&code), src);
+
+        let err = |line, msg| Err(AsmError { line, msg });
+        assert_eq!(assemble("nop +0\n\n"), err(2, "No OPCODE in input"));
+        assert_eq!(assemble("acc"), err(1, "No ARGUMENT in input"));
+        assert_eq!(assemble("acc +x"), err(1, "ARGUMENT is not a number"));
+        assert_eq!(
+            assemble("acc +1 +2"),
+            err(1, "Trailing input after ARGUMENT")
+        );
+        assert_eq!(
+            assemble("nop +0\nmul +1"),
+            err(2, "Invalid instruction encountered")
+        );
+    }
 }
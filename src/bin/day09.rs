@@ -1,97 +1,429 @@
-use std::io;
-
-/// Given `curr_val` in cypher and window of previous values `prev_vals`,
-/// check if `curr_val` full fills XMAS cypher requirements.
-/// - `curr_val` must be sum of any 2 values in `prev_vals`
-/// - summands must have different values
-fn is_valid(curr_val: usize, prev_vals: &[usize]) -> bool {
-    for (i, n1) in prev_vals.iter().enumerate() {
-        for n2 in prev_vals.iter().skip(i + 1) {
-            if n1 == n2 {
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead};
+
+/// Preamble length of the input cypher.
+const PREAMBLE: usize = 25;
+
+/// Number of values the oldest value of the window is randomly paired
+/// with when generating a valid value.
+const SUMMAND_CANDIDATES: usize = 4;
+
+/// Value violating the XMAS cypher at 0-based `idx` of the stream.
+#[derive(Debug, PartialEq)]
+struct Invalid {
+    idx: usize,
+    val: usize,
+}
+
+/// Streaming XMAS cypher checker.
+///
+/// After a preamble of `preamble` values, each value must be the sum of
+/// 2 of the `preamble` values before it, the summands must have different
+/// values. Sums of all pairs in the window are counted and updated
+/// incrementally as the window slides, so checking a value takes linear
+/// instead of quadratic time in the preamble length.
+struct CipherChecker {
+    preamble: usize,
+    window: VecDeque<usize>,
+    /// Number of pairs in `window` for each sum.
+    sums: HashMap<usize, usize>,
+    /// Index of the next value.
+    idx: usize,
+}
+
+impl CipherChecker {
+    fn new(preamble: usize) -> CipherChecker {
+        CipherChecker {
+            preamble,
+            window: VecDeque::with_capacity(preamble + 1),
+            sums: HashMap::new(),
+            idx: 0,
+        }
+    }
+
+    /// Whether `val` is valid as next value of the stream, values of the
+    /// preamble are always valid.
+    fn is_valid(&self, val: usize) -> bool {
+        self.idx < self.preamble || self.sums.contains_key(&val)
+    }
+
+    /// Check the next value of the stream.
+    fn push(&mut self, val: usize) -> Option<Invalid> {
+        let invalid = (!self.is_valid(val)).then_some(Invalid { idx: self.idx, val });
+        self.idx += 1;
+
+        if self.window.len() == self.preamble {
+            match self.window.pop_front() {
+                Some(old) => self.update_sums(old, false),
+                // Without preamble nothing is kept.
+                None => return invalid,
+            }
+        }
+        self.update_sums(val, true);
+        self.window.push_back(val);
+
+        invalid
+    }
+
+    /// Add or remove the sums of `val` paired with each value in the
+    /// window.
+    fn update_sums(&mut self, val: usize, add: bool) {
+        for &other in self.window.iter().filter(|&&other| other != val) {
+            let Some(sum) = val.checked_add(other) else {
                 continue;
+            };
+            if add {
+                *self.sums.entry(sum).or_default() += 1;
+            } else if let Some(cnt) = self.sums.get_mut(&sum) {
+                *cnt -= 1;
+                if *cnt == 0 {
+                    self.sums.remove(&sum);
+                }
             }
-            if n1 + n2 == curr_val {
-                return true;
+        }
+    }
+
+    /// Random valid next value, `None` if the window has no 2 different
+    /// values or their sum overflows.
+    ///
+    /// To keep the stream growing as slowly as possible, the oldest value
+    /// of the window is paired with one of the next `SUMMAND_CANDIDATES`
+    /// oldest values different from it. The sum replaces the oldest value
+    /// once it leaves the window.
+    fn valid_value(&self, rng: &mut Rng) -> Option<usize> {
+        let oldest = *self.window.front()?;
+        let candidates = self
+            .window
+            .iter()
+            .skip(1)
+            .filter(|&&n| n != oldest)
+            .take(SUMMAND_CANDIDATES);
+
+        let cnt = candidates.clone().count();
+        if cnt == 0 {
+            return None;
+        }
+        let other = candidates.clone().nth(rng.below(cnt))?;
+        oldest.checked_add(*other)
+    }
+
+    /// Random invalid next value, not larger than twice the largest
+    /// value in the window. Terminates as 0 is never valid.
+    fn invalid_value(&self, rng: &mut Rng) -> usize {
+        let max = self.window.iter().max().copied().unwrap_or_default();
+        let upper = max.saturating_mul(2).saturating_add(1);
+        loop {
+            let val = rng.below(upper);
+            if !self.is_valid(val) {
+                return val;
             }
         }
     }
-    false
 }
 
-fn challenge1() -> io::Result<usize> {
-    let input = aoc20::read_input_to_string("day09")
-        .lines()
-        .map(|n| {
-            n.parse::<usize>().map_err(|_| {
-                io::Error::new(io::ErrorKind::InvalidData, "Input line is not a number")
-            })
-        })
-        .collect::<io::Result<Vec<_>>>()?;
+/// Xorshift pseudo random number generator, deterministic for a seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // State must not be 0.
+        Rng(seed.max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Random number in `0..n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// Parameters of a stream created by `generate`.
+struct StreamSpec {
+    /// Preamble the stream starts with, also defines its length.
+    preamble: Vec<usize>,
+    /// Total number of values including the preamble.
+    len: usize,
+    /// Index to plant an invalid value at.
+    invalid_at: Option<usize>,
+    /// Make the invalid value the sum of a contiguous range of at least 2
+    /// numbers before it.
+    plant_range: bool,
+    seed: u64,
+}
 
-    const WLEN: usize = 25 /* previous window */ + 1 /* current value */;
+/// Stream created by `generate` with the planted values.
+#[derive(Debug)]
+struct Generated {
+    nums: Vec<usize>,
+    invalid: Option<Invalid>,
+    /// Planted range `start..=end` summing up to the invalid value.
+    range: Option<(usize, usize)>,
+}
 
-    for window in input.windows(WLEN) {
-        let (curr_val, prev_vals) = window.split_last().expect("WLEN > 2");
-        if !is_valid(*curr_val, prev_vals) {
-            return Ok(*curr_val);
+/// Number of random ranges tried when planting a range.
+const RANGE_ATTEMPTS: usize = 1000;
+
+/// Generate a stream satisfying the XMAS cypher, the inverse of
+/// `CipherChecker`.
+///
+/// Each value after the preamble is the sum of 2 values with different
+/// values in the window, except the value at `invalid_at`.
+///
+/// In any valid stream each value is larger than the smallest value of
+/// the window, so the smallest value at least doubles every `preamble`
+/// values and a stream fits into `usize` for at most about
+/// `64 * preamble` values. Summands are picked by
+/// `CipherChecker::valid_value` to get close to that bound, generation
+/// fails once values would overflow, which happens after roughly 1400
+/// values for a preamble of length 25 and 11000 values for a preamble
+/// of length 200.
+fn generate(spec: &StreamSpec) -> io::Result<Generated> {
+    let invalid_input = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+
+    let preamble = spec.preamble.len();
+    if spec.preamble.iter().all(|&n| n == spec.preamble[0]) {
+        return Err(invalid_input("Preamble needs 2 different values"));
+    } else if spec.len < preamble {
+        return Err(invalid_input("Stream is shorter than the preamble"));
+    } else if spec
+        .invalid_at
+        .is_some_and(|at| at < preamble || at >= spec.len)
+    {
+        return Err(invalid_input("Invalid value must be after the preamble"));
+    } else if spec.plant_range && spec.invalid_at.is_none() {
+        return Err(invalid_input("Range needs an invalid value"));
+    }
+
+    let mut rng = Rng::new(spec.seed);
+    let mut checker = CipherChecker::new(preamble);
+    let mut gen = Generated {
+        nums: Vec::with_capacity(spec.len),
+        invalid: None,
+        range: None,
+    };
+
+    for &val in &spec.preamble {
+        checker.push(val);
+        gen.nums.push(val);
+    }
+
+    while gen.nums.len() < spec.len {
+        let idx = gen.nums.len();
+        let val = if spec.invalid_at == Some(idx) {
+            let val = if spec.plant_range {
+                let (start, end, val) =
+                    plant_range(&gen.nums, &checker, &mut rng).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "No range found summing up to an invalid value",
+                        )
+                    })?;
+                gen.range = Some((start, end));
+                val
+            } else {
+                checker.invalid_value(&mut rng)
+            };
+            gen.invalid = Some(Invalid { idx, val });
+            val
+        } else {
+            checker.valid_value(&mut rng).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "No valid value without overflow found",
+                )
+            })?
+        };
+
+        checker.push(val);
+        gen.nums.push(val);
+    }
+
+    Ok(gen)
+}
+
+/// Random range `start..=end` of at least 2 numbers in `nums` whose sum
+/// is invalid as next value of `checker`.
+fn plant_range(
+    nums: &[usize],
+    checker: &CipherChecker,
+    rng: &mut Rng,
+) -> Option<(usize, usize, usize)> {
+    for _ in 0..RANGE_ATTEMPTS {
+        let start = rng.below(nums.len() - 1);
+        let end = start + 1 + rng.below(nums.len() - start - 1);
+        let sum = nums[start..=end]
+            .iter()
+            .try_fold(0usize, |sum, &n| sum.checked_add(n));
+        if let Some(val) = sum.filter(|&val| !checker.is_valid(val)) {
+            return Some((start, end, val));
         }
     }
+    None
+}
 
-    Err(io::Error::new(
-        io::ErrorKind::InvalidData,
-        "No invalid value found in input cypher",
-    ))
+/// All values of `nums` violating the XMAS cypher with a preamble of
+/// length `preamble`.
+fn invalid_values(
+    preamble: usize,
+    nums: impl IntoIterator<Item = usize>,
+) -> impl Iterator<Item = Invalid> {
+    let mut checker = CipherChecker::new(preamble);
+    nums.into_iter().filter_map(move |n| checker.push(n))
 }
 
-fn challenge2() -> io::Result<usize> {
-    let input = aoc20::read_input_to_string("day09")
-        .lines()
-        .map(|n| {
-            n.parse::<usize>().map_err(|_| {
-                io::Error::new(io::ErrorKind::InvalidData, "Input line is not a number")
-            })
-        })
-        .collect::<io::Result<Vec<_>>>()?;
+/// Numbers with one number per line.
+fn read_numbers(r: impl BufRead) -> impl Iterator<Item = io::Result<usize>> {
+    r.lines().map(|line| {
+        line?
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Input line is not a number"))
+    })
+}
 
-    // Invalid number in input cypher as determined in challenge1.
-    const INVALID_VAL: usize = 257342611;
+fn parse_cypher() -> io::Result<Vec<usize>> {
+    read_numbers(aoc20::input_bufreader("day09")).collect()
+}
 
-    for (i, n1) in input.iter().enumerate() {
-        let mut min = n1;
-        let mut max = n1;
+/// First value of `nums` violating the XMAS cypher.
+fn first_invalid(preamble: usize, nums: &[usize]) -> io::Result<usize> {
+    invalid_values(preamble, nums.iter().copied())
+        .next()
+        .map(|invalid| invalid.val)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "No invalid value found in input cypher",
+            )
+        })
+}
 
-        let mut sum = *n1;
-        for n2 in input.iter().skip(i + 1) {
-            sum += n2;
+/// Contiguous range `start..=end` of at least 2 numbers summing up to a
+/// target, `weakness` is the sum of its smallest and largest number.
+#[derive(Debug, PartialEq)]
+struct Weakness {
+    start: usize,
+    end: usize,
+    weakness: usize,
+}
 
-            // Overshoot, try again.
-            if sum > INVALID_VAL {
-                break;
-            }
+/// Find the first contiguous range of at least 2 numbers in `nums`
+/// summing up to `target`.
+///
+/// As all numbers are non-negative, a sliding window is shrunk at its
+/// start while adding the next number would make its sum too large and
+/// then grown at its end, which takes linear time. Shrinking first keeps
+/// the sum from overflowing.
+fn find_weakness(nums: &[usize], target: usize) -> Option<Weakness> {
+    let mut start = 0;
+    let mut sum = 0usize;
 
-            // Track min/max values in current sequence.
-            if n2 > max {
-                max = n2;
-            } else if n2 < min {
-                min = n2;
-            }
+    for (end, &n) in nums.iter().enumerate() {
+        while start < end && sum.checked_add(n).is_none_or(|s| s > target) {
+            sum -= nums[start];
+            start += 1;
+        }
+        // Window is empty or its sum with `n` is at most `target`.
+        sum += n;
 
-            // Check if current sequence matches the invalid number.
-            if sum == INVALID_VAL {
-                return Ok(min + max);
-            }
+        if sum == target && start < end {
+            let range = &nums[start..=end];
+            let min = range.iter().min().expect("range not empty");
+            let max = range.iter().max().expect("range not empty");
+            return Some(Weakness {
+                start,
+                end,
+                weakness: min + max,
+            });
         }
     }
+    None
+}
 
-    Err(io::Error::new(
-        io::ErrorKind::InvalidData,
-        "No sequence found that sums up to the invalid number",
-    ))
+fn challenge1() -> io::Result<usize> {
+    first_invalid(PREAMBLE, &parse_cypher()?)
+}
+
+fn challenge2() -> io::Result<usize> {
+    let input = parse_cypher()?;
+    let invalid = first_invalid(PREAMBLE, &input)?;
+
+    find_weakness(&input, invalid)
+        .map(|w| w.weakness)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "No sequence found that sums up to the invalid number",
+            )
+        })
+}
+
+fn usage() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Usage: day09 [check <preamble> | weakness [<target>] | \
+         generate <preamble> <len> [<invalid_at> [range]]]",
+    )
 }
 
 fn main() -> io::Result<()> {
-    println!("{}", challenge1()?);
-    println!("{}", challenge2()?);
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => {
+            println!("{}", challenge1()?);
+            println!("{}", challenge2()?);
+        }
+        [cmd, preamble] if cmd == "check" => {
+            // Stream numbers from stdin and report invalid values as they
+            // are encountered.
+            let mut checker = CipherChecker::new(preamble.parse().map_err(|_| usage())?);
+            for n in read_numbers(io::stdin().lock()) {
+                if let Some(Invalid { idx, val }) = checker.push(n?) {
+                    println!("{}: {}", idx, val);
+                }
+            }
+        }
+        [cmd, target @ ..] if cmd == "weakness" && target.len() <= 1 => {
+            let input = parse_cypher()?;
+            let target = match target.first() {
+                Some(target) => target.parse().map_err(|_| usage())?,
+                None => first_invalid(PREAMBLE, &input)?,
+            };
+            match find_weakness(&input, target) {
+                Some(w) => println!("{}..={}: {}", w.start, w.end, w.weakness),
+                None => println!("No range sums up to {}", target),
+            }
+        }
+        [cmd, preamble, len, invalid @ ..] if cmd == "generate" && invalid.len() <= 2 => {
+            let parse = |n: &String| n.parse::<usize>().map_err(|_| usage());
+            let plant_range = match invalid {
+                [_, range] if range == "range" => true,
+                [_, _] => return Err(usage()),
+                _ => false,
+            };
+            let seed = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64);
+
+            let gen = generate(&StreamSpec {
+                preamble: (1..=parse(preamble)?).collect(),
+                len: parse(len)?,
+                invalid_at: invalid.first().map(parse).transpose()?,
+                plant_range,
+                seed,
+            })?;
+            for n in gen.nums {
+                println!("{}", n);
+            }
+        }
+        _ => return Err(usage()),
+    }
     Ok(())
 }
 
@@ -105,6 +437,124 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn check_invalid_values() {
+        let nums = [
+            35, 20, 15, 25, 47, 40, 62, 55, 65, 95, 102, 117, 150, 182, 127, 219, 299, 277, 309,
+            576,
+        ];
+        let invalid: Vec<_> = invalid_values(5, nums).collect();
+        assert_eq!(invalid, vec![Invalid { idx: 14, val: 127 }]);
+
+        // Summands must have different values, sums leave with the window.
+        let nums = [2, 2, 3, 4, 5, 7, 5];
+        let invalid: Vec<_> = invalid_values(3, nums).collect();
+        assert_eq!(
+            invalid,
+            vec![Invalid { idx: 3, val: 4 }, Invalid { idx: 6, val: 5 }]
+        );
+
+        assert_eq!(invalid_values(0, [1, 2]).count(), 2);
+        assert_eq!(invalid_values(3, [5, 5, 5]).count(), 0);
+    }
+
+    #[test]
+    fn check_find_weakness() {
+        let nums = [
+            35, 20, 15, 25, 47, 40, 62, 55, 65, 95, 102, 117, 150, 182, 127, 219, 299, 277, 309,
+            576,
+        ];
+        assert_eq!(
+            find_weakness(&nums, 127),
+            Some(Weakness {
+                start: 2,
+                end: 5,
+                weakness: 62
+            })
+        );
+
+        // A single number is not a range.
+        assert_eq!(find_weakness(&[1, 5, 2, 3], 5).map(|w| w.start), Some(2));
+        assert_eq!(find_weakness(&[1, 5, 1], 5), None);
+        assert_eq!(find_weakness(&[], 0), None);
+        assert_eq!(find_weakness(&[usize::MAX, 1], 0), None);
+        assert_eq!(
+            find_weakness(&[usize::MAX, 1, 2], 3).map(|w| w.start),
+            Some(1)
+        );
+    }
+
+    /// Generate a stream of `len` values with an invalid value near its
+    /// end and check it.
+    fn check_long_stream(preamble: usize, len: usize) -> io::Result<()> {
+        let spec = StreamSpec {
+            preamble: (1..=preamble).collect(),
+            len,
+            invalid_at: Some(len - 100),
+            plant_range: false,
+            seed: 3,
+        };
+        let gen = generate(&spec)?;
+        let invalid: Vec<_> = invalid_values(preamble, gen.nums).collect();
+        assert_eq!(invalid, gen.invalid.into_iter().collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "takes several seconds in debug builds"]
+    fn check_generate_10k() -> io::Result<()> {
+        check_long_stream(200, 10_000)
+    }
+
+    #[test]
+    fn check_generate() -> io::Result<()> {
+        let mut spec = StreamSpec {
+            preamble: (1..=25).collect(),
+            len: 600,
+            invalid_at: None,
+            plant_range: false,
+            seed: 9,
+        };
+        let gen = generate(&spec)?;
+        assert_eq!(gen.nums.len(), 600);
+        assert_eq!(invalid_values(25, gen.nums).count(), 0);
+
+        spec.invalid_at = Some(400);
+        let gen = generate(&spec)?;
+        let invalid: Vec<_> = invalid_values(25, gen.nums).collect();
+        assert_eq!(invalid, gen.invalid.into_iter().collect::<Vec<_>>());
+        assert_eq!(invalid[0].idx, 400);
+
+        spec.plant_range = true;
+        for seed in 0..20 {
+            spec.seed = seed;
+            let gen = generate(&spec)?;
+            let invalid = first_invalid(25, &gen.nums)?;
+            let (start, end) = gen.range.expect("range planted");
+            assert!(start < end && end < 400);
+            assert_eq!(gen.nums[start..=end].iter().sum::<usize>(), invalid);
+
+            let w = find_weakness(&gen.nums, invalid).expect("range planted");
+            assert_eq!(gen.nums[w.start..=w.end].iter().sum::<usize>(), invalid);
+        }
+
+        // Long streams need a long preamble, as values at least double
+        // every preamble.
+        check_long_stream(40, 2_000)?;
+
+        spec.len = 1_400;
+        spec.invalid_at = None;
+        spec.plant_range = false;
+        assert_eq!(invalid_values(25, generate(&spec)?.nums).count(), 0);
+
+        spec.preamble = vec![3, 3];
+        assert!(generate(&spec).is_err());
+        spec.preamble = vec![1, 2];
+        spec.invalid_at = Some(1);
+        assert!(generate(&spec).is_err());
+        Ok(())
+    }
+
     #[test]
     fn check_challenge2() -> io::Result<()> {
         assert_eq!(challenge2()?, 35602097);
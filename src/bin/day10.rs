@@ -1,59 +1,161 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::io;
 
-fn challenge1(adapters: &Vec<usize>) -> usize {
-    let (cd1, cd3) = adapters
+/// Maximum joltage difference an adapter can bridge in the puzzle.
+const MAX_GAP: usize = 3;
+
+/// Adapter chain breaks between the adapters at `idx - 1` and `idx` as
+/// the step from `from` to `to` jolts is not within `1..=max_gap`.
+#[derive(Debug, PartialEq)]
+struct ChainError {
+    idx: usize,
+    from: usize,
+    to: usize,
+    max_gap: usize,
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Adapter chain breaks at adapter {}: {} -> {} jolts is not a step of 1 to {} jolts",
+            self.idx, self.from, self.to, self.max_gap
+        )
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+impl From<ChainError> for io::Error {
+    fn from(e: ChainError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Check that each adapter of the sorted chain accepts the joltage of the
+/// previous one.
+fn validate(adapters: &[usize], max_gap: usize) -> Result<(), ChainError> {
+    match adapters
         .windows(2)
-        .fold((0, 0), |(cd1, cd3), w| match w[1] - w[0] {
-            1 => (cd1 + 1, cd3),
-            2 => (cd1, cd3),
-            3 => (cd1, cd3 + 1),
-            _ => unreachable!(),
-        });
-    cd1 * cd3
+        .position(|w| !(1..=max_gap).contains(&(w[1] - w[0])))
+    {
+        Some(pos) => Err(ChainError {
+            idx: pos + 1,
+            from: adapters[pos],
+            to: adapters[pos + 1],
+            max_gap,
+        }),
+        None => Ok(()),
+    }
 }
 
-fn challenge2(adapters: &Vec<usize>) -> usize {
-    let mut variants: HashMap<usize, usize> = HashMap::new();
+/// Number of occurrences of each joltage difference in the sorted chain.
+fn histogram(adapters: &[usize]) -> BTreeMap<usize, usize> {
+    let mut hist = BTreeMap::new();
+    for w in adapters.windows(2) {
+        *hist.entry(w[1] - w[0]).or_insert(0) += 1;
+    }
+    hist
+}
+
+fn challenge1(adapters: &[usize], max_gap: usize) -> Result<usize, ChainError> {
+    validate(adapters, max_gap)?;
+
+    let hist = histogram(adapters);
+    let count = |diff| hist.get(&diff).copied().unwrap_or(0);
+    Ok(count(1) * count(3))
+}
+
+fn challenge2(adapters: &[usize], max_gap: usize) -> io::Result<usize> {
+    validate(adapters, max_gap)?;
+
+    let mut variants = vec![0; adapters.len()];
 
     // Start with single variant for adapter `0`.
-    variants.insert(0, 1); // initial coin
-
-    for &adapter in &adapters[1..] {
-        // For each adatper sum up the variants of the previous adapters in range [-3, 0).
-        let adapter_variants = variants.get(&adapter.wrapping_sub(1)).unwrap_or(&0)
-            + variants.get(&adapter.wrapping_sub(2)).unwrap_or(&0)
-            + variants.get(&adapter.wrapping_sub(3)).unwrap_or(&0);
-        variants.insert(adapter, adapter_variants);
+    variants[0] = 1; // initial coin
+
+    for (i, &adapter) in adapters.iter().enumerate().skip(1) {
+        // For each adatper sum up the variants of the previous adapters in range [-max_gap, 0).
+        variants[i] = (0..i)
+            .rev()
+            .take_while(|&j| adapter - adapters[j] <= max_gap)
+            .try_fold(0usize, |sum, j| sum.checked_add(variants[j]))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Number of arrangements overflows",
+                )
+            })?;
     }
-    variants[adapters.last().expect("Definetly have some adapters")]
+    Ok(*variants.last().expect("Definetly have some adapters"))
 }
 
-fn parse_adapters() -> io::Result<Vec<usize>> {
-    let mut v = aoc20::read_input_to_string("day10")
-        .lines()
-        .map(|l| {
-            l.parse()
-                .map_err(|_| io::Error::new(io::ErrorKind::Other, ""))
-        })
-        .collect::<io::Result<Vec<usize>>>()?;
-
+/// Build the adapter chain from the joltage ratings of the bag adapters.
+fn build_chain(mut v: Vec<usize>, max_gap: usize) -> io::Result<Vec<usize>> {
     // Add chargin outlet (0 jolts).
     v.push(0);
 
     // Build adapter chain.
     v.sort();
 
-    // Add devices built-in adapter (last adapter + 3).
-    v.push(v.last().unwrap() + 3);
+    // Add devices built-in adapter (last adapter + max gap).
+    let device = v.last().unwrap().checked_add(max_gap).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Max gap overflows the device joltage",
+        )
+    })?;
+    v.push(device);
 
     Ok(v)
 }
 
+fn parse_adapters(max_gap: usize) -> io::Result<Vec<usize>> {
+    let v = aoc20::read_input_to_string("day10")
+        .lines()
+        .map(|l| {
+            l.parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "Input line is not a number")
+            })
+        })
+        .collect::<io::Result<Vec<usize>>>()?;
+
+    build_chain(v, max_gap)
+}
+
+fn usage() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Usage: day10 [--max-gap <n>] [histogram]",
+    )
+}
+
 fn main() -> io::Result<()> {
-    let adapters = parse_adapters()?;
-    println!("{}", challenge1(&adapters));
-    println!("{}", challenge2(&adapters));
+    let mut args: Vec<_> = std::env::args().skip(1).collect();
+
+    let mut max_gap = MAX_GAP;
+    if args.first().map(String::as_str) == Some("--max-gap") {
+        max_gap = args.get(1).and_then(|n| n.parse().ok()).ok_or_else(usage)?;
+        args.drain(..2);
+    }
+
+    let adapters = parse_adapters(max_gap)?;
+    match args.as_slice() {
+        [] => {
+            println!("{}", challenge1(&adapters, max_gap)?);
+            println!("{}", challenge2(&adapters, max_gap)?);
+        }
+        [cmd] if cmd == "histogram" => {
+            for (diff, cnt) in histogram(&adapters) {
+                println!("{}: {}", diff, cnt);
+            }
+            if let Err(e) = validate(&adapters, max_gap) {
+                println!("{}", e);
+            }
+        }
+        _ => return Err(usage()),
+    }
     Ok(())
 }
 
@@ -63,15 +165,51 @@ mod tests {
 
     #[test]
     fn check_challenge1() -> io::Result<()> {
-        let a = parse_adapters()?;
-        assert_eq!(challenge1(&a), 2176);
+        let a = parse_adapters(MAX_GAP)?;
+        assert_eq!(challenge1(&a, MAX_GAP)?, 2176);
         Ok(())
     }
 
     #[test]
     fn check_challenge2() -> io::Result<()> {
-        let a = parse_adapters()?;
-        assert_eq!(challenge2(&a), 18512297918464);
+        let a = parse_adapters(MAX_GAP)?;
+        assert_eq!(challenge2(&a, MAX_GAP)?, 18512297918464);
+        Ok(())
+    }
+
+    #[test]
+    fn check_max_gap() -> io::Result<()> {
+        let a = build_chain(vec![16, 10, 15, 5, 1, 11, 7, 19, 6, 12, 4], MAX_GAP)?;
+        assert_eq!(histogram(&a), BTreeMap::from([(1, 7), (3, 5)]));
+        assert_eq!(challenge1(&a, MAX_GAP), Ok(35));
+        assert_eq!(challenge2(&a, MAX_GAP)?, 8);
+
+        // Larger gaps allow more arrangements, the built-in adapter follows
+        // the gap.
+        let a = build_chain(vec![1, 2, 3, 4], 4)?;
+        assert_eq!(*a.last().unwrap(), 8);
+        assert_eq!(challenge2(&a, 4)?, 8);
+
+        let a = build_chain(vec![1, 2, 6, 7], 3)?;
+        assert_eq!(histogram(&a), BTreeMap::from([(1, 3), (3, 1), (4, 1)]));
+        let err = ChainError {
+            idx: 3,
+            from: 2,
+            to: 6,
+            max_gap: 3,
+        };
+        assert_eq!(challenge1(&a, 3), Err(err));
+        assert_eq!(challenge2(&a, 4)?, 4);
+
+        let a = build_chain(vec![1, 2, 2], 3)?;
+        assert_eq!(validate(&a, 3).map_err(|e| e.idx), Err(3));
+
+        // Huge gaps neither overflow nor depend on the gap for run time.
+        assert!(build_chain(vec![1], usize::MAX).is_err());
+        let a = build_chain(vec![1, 2, 3], 1 << 40)?;
+        assert_eq!(challenge2(&a, 1 << 40)?, 4);
+        let a = build_chain((1..100).collect(), 1 << 40)?;
+        assert!(challenge2(&a, 1 << 40).is_err());
         Ok(())
     }
 }